@@ -0,0 +1,17 @@
+-- Package collaborators: package_owners holds owners, maintainers and
+-- publishers, invitations wait in package_invites until they are accepted.
+
+ALTER TABLE package_owners DROP CONSTRAINT package_owners_role_check;
+ALTER TABLE package_owners ADD CONSTRAINT package_owners_role_check
+  CHECK (role IN ('owner', 'maintainer', 'publisher'));
+
+CREATE TABLE package_invites (
+  package VARCHAR(40) NOT NULL REFERENCES packages (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  userName VARCHAR(20) NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  role VARCHAR(20) NOT NULL CHECK (role IN ('owner', 'maintainer', 'publisher')),
+  invitedBy VARCHAR(20) NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  createdAt timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (package, userName)
+);
+
+CREATE INDEX package_invites_userName_idx ON package_invites (userName);
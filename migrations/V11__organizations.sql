@@ -0,0 +1,27 @@
+-- Organizations own packages on behalf of their members, who hold the same
+-- roles as package collaborators on every package of the organization. Users
+-- and organizations share one namespace, so packages.owner names exactly one
+-- account.
+
+CREATE TABLE organizations (
+  name VARCHAR(20) NOT NULL UNIQUE,
+  normalizedName VARCHAR(20) NOT NULL UNIQUE,
+  description TEXT NOT NULL DEFAULT '',
+  createdAt timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE TABLE organization_members (
+  organization VARCHAR(20) NOT NULL REFERENCES organizations (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  userName VARCHAR(20) NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  role VARCHAR(20) NOT NULL CHECK (role IN ('owner', 'maintainer', 'publisher')),
+  createdAt timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (organization, userName)
+);
+
+CREATE INDEX organization_members_userName_idx ON organization_members (userName);
+
+-- tokens of an organization publish any of its packages
+ALTER TABLE tokens ADD COLUMN organization VARCHAR(20)
+  REFERENCES organizations (name) ON UPDATE CASCADE ON DELETE CASCADE;
+
+CREATE INDEX packages_owner_idx ON packages (owner);
@@ -0,0 +1,4 @@
+-- An upload is named `<package>@<version>`, so its name has to fit a package
+-- name (40), the separator and a version (20)
+
+ALTER TABLE "package-uploads" ALTER COLUMN name TYPE VARCHAR(61);
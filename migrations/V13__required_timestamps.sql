@@ -0,0 +1,14 @@
+-- Rows from before the API recorded timestamps have NULL createdAt and
+-- updatedAt. Backfill them with the best value at hand, falling back to the
+-- epoch that ordering already sorted them as, so the columns can be required.
+
+UPDATE users SET createdAt = 'epoch' WHERE createdAt IS NULL;
+UPDATE packages SET createdAt = COALESCE(updatedAt, 'epoch') WHERE createdAt IS NULL;
+UPDATE packages SET updatedAt = createdAt WHERE updatedAt IS NULL;
+UPDATE "package-uploads" SET createdAt = 'epoch' WHERE createdAt IS NULL;
+
+ALTER TABLE users ALTER COLUMN createdAt SET DEFAULT now(), ALTER COLUMN createdAt SET NOT NULL;
+ALTER TABLE packages
+  ALTER COLUMN createdAt SET DEFAULT now(), ALTER COLUMN createdAt SET NOT NULL,
+  ALTER COLUMN updatedAt SET DEFAULT now(), ALTER COLUMN updatedAt SET NOT NULL;
+ALTER TABLE "package-uploads" ALTER COLUMN createdAt SET DEFAULT now(), ALTER COLUMN createdAt SET NOT NULL;
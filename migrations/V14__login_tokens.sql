@@ -0,0 +1,5 @@
+-- Tokens handed out by `login`, so the number each account holds can be
+-- capped. Earlier login tokens can't be told apart from tokens the user named
+-- "login" and are left to expire.
+
+ALTER TABLE tokens ADD COLUMN login BOOLEAN NOT NULL DEFAULT false;
@@ -1,6 +1,7 @@
--- psql -U nest -W -d nest -h localhost
+-- Initial nest.land schema. Uses IF NOT EXISTS so databases that were set up
+-- by hand from the old init.sql can adopt the migration history as is.
 
-CREATE TABLE users (
+CREATE TABLE IF NOT EXISTS users (
   name VARCHAR(20) NOT NULL UNIQUE,
   normalizedName VARCHAR(20) NOT NULL UNIQUE,
   password VARCHAR(256) NOT NULL,
@@ -9,7 +10,7 @@ CREATE TABLE users (
   createdAt timestamptz
 );
 
-CREATE TABLE packages (
+CREATE TABLE IF NOT EXISTS packages (
   name VARCHAR(40) NOT NULL UNIQUE,
   normalizedName VARCHAR(40) NOT NULL UNIQUE,
   owner VARCHAR(250) NOT NULL,
@@ -25,7 +26,7 @@ CREATE TABLE packages (
   createdAt timestamptz
 );
 
-CREATE TABLE "package-uploads" (
+CREATE TABLE IF NOT EXISTS "package-uploads" (
   name VARCHAR(40) NOT NULL UNIQUE,
   package VARCHAR(40) NOT NULL,
   entry VARCHAR(60),
@@ -35,6 +36,3 @@ CREATE TABLE "package-uploads" (
   files JSON,
   createdAt timestamptz
 );
-
--- INSERT INTO users (name, normalizedName, password, apiKey, packageNames, createdAt) VALUES ('divy', 'divy', 'weird-password@ok-boomer', 'haha', ARRAY [ 'sass' ], '2016-06-22 19:10:25-07');
--- INSERT INTO packages (name, normalizedName, owner, description, repository, latestVersion, latestStableVersion, packageUploadNames, locked, malicious, unlisted, createdAt, updatedAt) VALUES ('sass', 'sass', 'divy', 'Deno Sass Compiler', 'https://github.com/divy-work/deno-sass', 'v0.2.0', 'v0.2.0', ARRAY ['sass'], false, false, false, '2016-06-22 19:10:25-07', '2016-06-22 19:10:25-07');
@@ -0,0 +1,5 @@
+-- Track failed logins per account so repeated password guesses can be throttled.
+
+ALTER TABLE users
+  ADD COLUMN failedLogins INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN lastFailedLoginAt timestamptz;
@@ -0,0 +1,17 @@
+-- Named API tokens. A user can hold any number of them, each limited to a set
+-- of scopes and optionally to a single package.
+
+CREATE TABLE IF NOT EXISTS tokens (
+  id VARCHAR(32) PRIMARY KEY,
+  userName VARCHAR(20) NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  name VARCHAR(64) NOT NULL,
+  token VARCHAR(256) NOT NULL UNIQUE,
+  scopes VARCHAR [] NOT NULL,
+  package VARCHAR(40) REFERENCES packages (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  expiresAt timestamptz,
+  lastUsedAt timestamptz,
+  revokedAt timestamptz,
+  createdAt timestamptz NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS tokens_userName_idx ON tokens (userName);
@@ -0,0 +1,9 @@
+-- API keys and tokens are only stored as keyed hashes, along with a short
+-- prefix that identifies them. Rows whose prefix is still NULL hold a
+-- plaintext key; the server hashes those on startup.
+
+ALTER TABLE users RENAME COLUMN apiKey TO apiKeyHash;
+ALTER TABLE users ADD COLUMN apiKeyPrefix VARCHAR(12);
+
+ALTER TABLE tokens RENAME COLUMN token TO tokenHash;
+ALTER TABLE tokens ADD COLUMN prefix VARCHAR(12);
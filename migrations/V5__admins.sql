@@ -0,0 +1,3 @@
+-- Site administrators may read the private data of every account.
+
+ALTER TABLE users ADD COLUMN admin BOOLEAN NOT NULL DEFAULT false;
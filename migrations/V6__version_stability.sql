@@ -0,0 +1,4 @@
+-- Whether a version was published as stable. Together with the semver
+-- prerelease rules this decides a package's latestStableVersion.
+
+ALTER TABLE "package-uploads" ADD COLUMN stable BOOLEAN NOT NULL DEFAULT true;
@@ -0,0 +1,4 @@
+-- Yanked versions stay downloadable by exact name but are skipped when
+-- resolving ranges and computing a package's latest versions.
+
+ALTER TABLE "package-uploads" ADD COLUMN yanked BOOLEAN NOT NULL DEFAULT false;
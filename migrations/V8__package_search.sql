@@ -0,0 +1,18 @@
+-- Full-text search over packages. Keywords are set by publishers and downloads
+-- count how often a version's entry module is served; both feed the ranking.
+
+ALTER TABLE packages ADD COLUMN keywords VARCHAR[] NOT NULL DEFAULT '{}';
+ALTER TABLE packages ADD COLUMN downloads INTEGER NOT NULL DEFAULT 0;
+
+-- array_to_string is only STABLE, generated columns need IMMUTABLE expressions
+CREATE FUNCTION keywords_to_text(VARCHAR[]) RETURNS TEXT
+  LANGUAGE sql IMMUTABLE AS $$ SELECT array_to_string($1, ' ') $$;
+
+ALTER TABLE packages ADD COLUMN searchVector tsvector GENERATED ALWAYS AS (
+  setweight(to_tsvector('simple', name), 'A') ||
+  setweight(to_tsvector('simple', normalizedName), 'A') ||
+  setweight(to_tsvector('simple', keywords_to_text(keywords)), 'B') ||
+  setweight(to_tsvector('simple', coalesce(description, '')), 'C')
+) STORED;
+
+CREATE INDEX packages_searchVector_idx ON packages USING GIN (searchVector);
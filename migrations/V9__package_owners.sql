@@ -0,0 +1,27 @@
+-- Who may manage a package, replacing the users.packageNames arrays.
+-- packages.owner stays as the account a package belongs to.
+
+CREATE TABLE package_owners (
+  package VARCHAR(40) NOT NULL REFERENCES packages (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  userName VARCHAR(20) NOT NULL REFERENCES users (name) ON UPDATE CASCADE ON DELETE CASCADE,
+  role VARCHAR(20) NOT NULL DEFAULT 'owner' CHECK (role IN ('owner')),
+  createdAt timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (package, userName)
+);
+
+CREATE INDEX package_owners_userName_idx ON package_owners (userName);
+
+-- carry over both records of ownership, skipping names that point nowhere
+INSERT INTO package_owners (package, userName, role, createdAt)
+  SELECT packages.name, users.name, 'owner', COALESCE(packages.createdAt, now())
+  FROM packages JOIN users ON users.name = packages.owner
+  ON CONFLICT DO NOTHING;
+
+INSERT INTO package_owners (package, userName, role, createdAt)
+  SELECT packages.name, users.name, 'owner', COALESCE(packages.createdAt, now())
+  FROM users
+  CROSS JOIN LATERAL unnest(users.packageNames) AS owned (name)
+  JOIN packages ON packages.name = owned.name
+  ON CONFLICT DO NOTHING;
+
+ALTER TABLE users DROP COLUMN packageNames;
@@ -0,0 +1,79 @@
+//! Authorization of API keys and tokens
+use crate::schema::{TokenScope, User};
+
+// The user behind an API key or token, along with what the credential may do
+pub struct Auth {
+    pub user: User,
+    pub scopes: Vec<TokenScope>,
+    pub package: Option<String>,
+    // set for organization tokens, which act for the organization rather than
+    // for the user who created them
+    pub organization: Option<String>,
+}
+
+impl Auth {
+    // admin tokens are allowed everything
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&TokenScope::Admin)
+    }
+
+    // whether the credential may read the private data of `user_name`, which
+    // only the user themselves and site admins can
+    pub fn can_view_private(&self, user_name: &str) -> bool {
+        self.has_scope(TokenScope::ReadPrivate) && (self.user.name == user_name || self.user.admin)
+    }
+
+    // whether the credential may publish `package`; ownership of the package
+    // is checked separately
+    pub fn can_publish(&self, package: &str) -> bool {
+        self.has_scope(TokenScope::Publish) && self.package.as_deref().is_none_or(|x| x == package)
+    }
+
+    // whether a token with `scopes`, limited to `package`, reaches no further
+    // than the credential itself, so that it may hand that token out
+    pub fn covers(&self, scopes: &[TokenScope], package: Option<&str>) -> bool {
+        scopes.iter().all(|x| self.has_scope(*x))
+            && self.package.as_deref().is_none_or(|x| package == Some(x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn auth(scopes: Vec<TokenScope>, package: Option<&str>) -> Auth {
+        Auth {
+            user: User {
+                name: "divy".to_string(),
+                normalized_name: "divy".to_string(),
+                api_key: None,
+                api_key_prefix: String::new(),
+                package_names: vec![],
+                admin: false,
+                created_at: Utc::now(),
+            },
+            scopes,
+            package: package.map(str::to_string),
+            organization: None,
+        }
+    }
+
+    #[test]
+    fn covers_narrower_scopes() {
+        let admin = auth(vec![TokenScope::Admin], None);
+        assert!(admin.covers(&[TokenScope::Admin, TokenScope::Publish], Some("sass")));
+        let publish = auth(vec![TokenScope::Publish], None);
+        assert!(publish.covers(&[TokenScope::Publish], None));
+        assert!(!publish.covers(&[TokenScope::Publish, TokenScope::ReadPrivate], None));
+        assert!(!publish.covers(&[TokenScope::Admin], None));
+    }
+
+    #[test]
+    fn covers_only_its_own_package() {
+        let limited = auth(vec![TokenScope::Admin], Some("sass"));
+        assert!(limited.covers(&[TokenScope::Publish], Some("sass")));
+        assert!(!limited.covers(&[TokenScope::Publish], Some("eggs")));
+        assert!(!limited.covers(&[TokenScope::Publish], None));
+    }
+}
@@ -1,9 +1,13 @@
-use std::sync::Arc;
-use tokio_postgres::Client;
+use crate::auth::Auth;
+use crate::db::Pool;
+use crate::loader::Loaders;
 // The GraphQL context, which needs to provide everything necessary for
-// interacting with the database.
+// interacting with the database, along with the authenticated caller and the
+// loaders batching relationship lookups of the request.
 pub struct GraphQLContext {
-    pub pool: Arc<Client>,
+    pub pool: Pool,
+    pub auth: Option<Auth>,
+    pub loaders: Loaders,
 }
 
 // This impl allows us to pass in GraphQLContext as the Context for GraphQL
@@ -1,293 +1,1821 @@
 // Postgres database management for Nest API
 
-use crate::schema::{NewPackage, NewPackageResult, NewPackageUpload, NewUser, Package, PublicUser, User};
-use crate::utils::{create_api_key, first, normalize};
-use chrono::{DateTime, Utc};
-use dotenv;
+use crate::auth::Auth;
+use crate::error::{ApiError, ApiResult};
+use crate::password;
+use crate::schema::{
+    Collaborator, Invite, ModuleFilter, ModuleOrder, ModuleOrderField, NewPackage, NewPackageUpload,
+    Organization, OrganizationMember, PackageRole,
+    NewToken, NewTokenResult, NewUser, OrderDirection, Package, PackageConnection, PackageEdge,
+    PackageFile, PackageVersion, PageInfo, PublicUser, SearchConnection, SearchEdge, Token,
+    TokenScope, User, UserConnection, UserEdge, UserOrder, UserOrderField,
+};
+use crate::serve::manifest_path;
+use crate::utils::{
+    api_key_prefix, create_api_key, decode_cursor, decode_position, encode_cursor,
+    encode_position, env_or, first, hash_api_key, is_reserved_name,
+    is_valid_package_name, normalize, parse_version, prefix_tsquery,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
 use postgres_array::array::Array;
-use std::sync::Arc;
-use tokio_postgres::{Client, Error, NoTls};
-use serde::{Deserialize, Serialize};
 use postgres_types::Json;
-use postgres_types::{FromSql};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use bb8::{ErrorSink, RunError};
+use bb8_postgres::PostgresConnectionManager;
+use semver::{Version, VersionReq};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio_postgres::types::ToSql;
+use futures::future::{BoxFuture, FutureExt};
+use tokio_postgres::{Config, Error, GenericClient, IsolationLevel, NoTls, Row, Transaction};
+
+pub type Pool = bb8::Pool<PostgresConnectionManager<NoTls>>;
+pub type PoolError = RunError<Error>;
+
+// Forward connection errors that the pool swallows to stderr
+#[derive(Debug, Clone, Copy)]
+struct LogErrorSink;
 
-// establish connection with Postgres db
-pub async fn connect() -> Result<Client, Error> {
+impl ErrorSink<Error> for LogErrorSink {
+    fn sink(&self, e: Error) {
+        eprintln!("connection error: {}", e);
+    }
+
+    fn boxed_clone(&self) -> Box<dyn ErrorSink<Error>> {
+        Box::new(*self)
+    }
+}
+
+// establish a pool of connections with the Postgres db
+pub async fn connect() -> Result<Pool, Error> {
     let host = dotenv::var("DB_HOST").unwrap_or("localhost".to_string());
     let user = dotenv::var("DB_USER").unwrap_or("nest".to_string());
     let database_name = dotenv::var("DB_NAME").unwrap_or("nest".to_string());
     let pass = dotenv::var("DB_PASS").unwrap_or("123".to_string());
-    let (client, connection) = tokio_postgres::connect(
-        &format!(
-            "host={} user={} dbname={} password={}",
-            host, user, database_name, pass
-        ),
-        NoTls,
+    let config: Config = format!(
+        "host={} user={} dbname={} password={}",
+        host, user, database_name, pass
     )
-    .await?;
+    .parse()?;
+
+    // Broken connections are dropped on checkout and replaced by fresh ones,
+    // so the pool recovers on its own once the database is reachable again.
+    Pool::builder()
+        .max_size(env_or("DB_POOL_SIZE", 16))
+        .min_idle(Some(env_or("DB_POOL_MIN_IDLE", 1)))
+        .connection_timeout(Duration::from_secs(env_or("DB_POOL_TIMEOUT", 30)))
+        .idle_timeout(Some(Duration::from_secs(env_or("DB_POOL_IDLE_TIMEOUT", 600))))
+        .test_on_check_out(true)
+        .error_sink(Box::new(LogErrorSink))
+        .build(PostgresConnectionManager::new(config, NoTls))
+        .await
+}
 
-    // The connection object performs the actual communication with the database,
-    // so spawn it off to run on its own.
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
+// Attempts at a serializable transaction before the conflict is reported
+const MAX_TX_ATTEMPTS: u32 = 10;
+
+// Run `f` with `args` in a serializable transaction and commit it. Postgres
+// aborts one of two concurrent transactions that can't be ordered one after the
+// other, in which case `f` runs again on a fresh transaction, so it must not
+// have side effects outside the database.
+async fn serializable<A, T, F>(db: &Pool, args: &A, f: F) -> ApiResult<T>
+where
+    A: Sync,
+    F: for<'t> Fn(&'t Transaction<'t>, &'t A) -> BoxFuture<'t, ApiResult<T>>,
+{
+    let mut conn = db.get().await?;
+    let mut attempt = 1;
+    loop {
+        let tx = conn
+            .build_transaction()
+            .isolation_level(IsolationLevel::Serializable)
+            .start()
+            .await?;
+        let res = match f(&tx, args).await {
+            Ok(x) => tx.commit().await.map(|_| x).map_err(ApiError::from),
+            Err(e) => Err(e),
+        };
+        match res {
+            Err(ApiError::Serialization) if attempt < MAX_TX_ATTEMPTS => {
+                // back off for a random while so the contenders don't collide again
+                let backoff = rand::thread_rng().gen_range(0, 10 << attempt.min(6));
+                actix_rt::time::delay_for(Duration::from_millis(backoff)).await;
+                attempt += 1;
+            }
+            res => return res,
         }
-    });
+    }
+}
+
+// Packages that never had a version uploaded have no latest versions yet
+fn package_from_row(row: &Row) -> Package {
+    let upload_names: Array<String> = row.get("packageUploadNames");
+    let keywords: Array<String> = row.get("keywords");
+    Package {
+        name: row.get("name"),
+        normalized_name: row.get("normalizedName"),
+        owner: row.get("owner"),
+        description: row.get::<_, Option<String>>("description").unwrap_or_default(),
+        repository: row.get::<_, Option<String>>("repository").unwrap_or_default(),
+        latest_version: row.get("latestVersion"),
+        latest_stable_version: row.get("latestStableVersion"),
+        package_upload_names: upload_names.iter().cloned().collect(),
+        keywords: keywords.iter().cloned().collect(),
+        downloads: row.get("downloads"),
+        locked: row.get("locked"),
+        malicious: row.get("malicious"),
+        unlisted: row.get("unlisted"),
+        updated_at: row.get("updatedAt"),
+        created_at: row.get("createdAt"),
+    }
+}
 
-    Ok(client)
+// What a list is ordered by: the expression, how to read it back from the
+// `sortKey` column for cursors and how to bind a cursor's value again
+#[derive(Clone, Copy)]
+enum SortKey {
+    Text(&'static str),
+    Time(&'static str),
+    Int(&'static str),
 }
 
-// Method to retrieve all modules from db
-pub async fn get_modules(db: Arc<Client>) -> Result<Vec<Package>, String> {
-    let rows = &db
-        .query("SELECT * FROM packages", &[])
-        .await
-        .unwrap();
-    let mut modules: Vec<Package> = Vec::new();
-    for i in 0..rows.len() {
-        let row = &rows[i];
-        let upload_names: Array<String> = row.get(7);
-        modules.push(Package {
-            name: row.get(0),
-            normalized_name: row.get(1),
-            owner: row.get(2),
-            description: row.get(3),
-            repository: row.get(4),
-            latest_version: row.get(5),
-            latest_stable_version: row.get(6),
-            package_upload_names: upload_names.iter().cloned().collect(),
-            locked: row.get(8),
-            malicious: row.get(9),
-            unlisted: row.get(10),
-            updated_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(11)),
-            created_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(12)),
-        });
-    }
-    Ok(modules)
+impl SortKey {
+    fn expr(self) -> &'static str {
+        match self {
+            SortKey::Text(x) | SortKey::Time(x) | SortKey::Int(x) => x,
+        }
+    }
+
+    fn value(self, row: &Row) -> String {
+        match self {
+            SortKey::Text(_) => row.get("sortKey"),
+            SortKey::Time(_) => row
+                .get::<_, DateTime<Utc>>("sortKey")
+                .to_rfc3339_opts(SecondsFormat::Micros, true),
+            SortKey::Int(_) => row.get::<_, i32>("sortKey").to_string(),
+        }
+    }
+
+    fn param(self, value: &str) -> Option<Box<dyn ToSql + Sync + Send>> {
+        Some(match self {
+            SortKey::Text(_) => Box::new(value.to_string()),
+            SortKey::Time(_) => Box::new(DateTime::parse_from_rfc3339(value).ok()?.with_timezone(&Utc)),
+            SortKey::Int(_) => Box::new(value.parse::<i32>().ok()?),
+        })
+    }
+}
+
+// The WHERE clauses of a query together with the parameters they bind
+#[derive(Default)]
+struct Conditions {
+    clauses: Vec<String>,
+    params: Vec<Box<dyn ToSql + Sync + Send>>,
+}
+
+impl Conditions {
+    // require `column <op> value`
+    fn push<T: ToSql + Sync + Send + 'static>(&mut self, column: &str, op: &str, value: T) {
+        self.params.push(Box::new(value));
+        self.clauses.push(format!("{} {} ${}", column, op, self.params.len()));
+    }
+}
+
+// Fetch a page of `table` with keyset pagination, ordered by `sort` and then by
+// name. Cursors hold the sort value and the name of a row, so pages stay
+// stable while rows are added.
+async fn query_page(
+    db: &Pool,
+    table: &str,
+    conditions: Conditions,
+    sort: SortKey,
+    direction: OrderDirection,
+    first: Option<i32>,
+    after: Option<String>,
+) -> ApiResult<(Vec<(String, Row)>, PageInfo)> {
+    let first = i64::from(first.unwrap_or(20).clamp(1, 100));
+    let (op, order) = match direction {
+        OrderDirection::Asc => (">", "ASC"),
+        OrderDirection::Desc => ("<", "DESC"),
+    };
+    let Conditions {
+        mut clauses,
+        mut params,
+    } = conditions;
+    if let Some(cursor) = &after {
+        let invalid = || ApiError::Validation("Invalid cursor".to_string());
+        let position = decode_cursor(cursor).ok_or_else(invalid)?;
+        let (value, name) = decode_position(&position).ok_or_else(invalid)?;
+        params.push(sort.param(&value).ok_or_else(invalid)?);
+        params.push(Box::new(name));
+        clauses.push(format!(
+            "({}, name) {} (${}, ${})",
+            sort.expr(),
+            op,
+            params.len() - 1,
+            params.len()
+        ));
+    }
+    // fetch one extra row to learn whether there is a next page
+    params.push(Box::new(first + 1));
+    let sql = format!(
+        "SELECT *, {expr} AS sortKey FROM {table} {where_} ORDER BY {expr} {order}, name {order} LIMIT ${limit}",
+        expr = sort.expr(),
+        table = table,
+        where_ = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        },
+        order = order,
+        limit = params.len(),
+    );
+    let conn = db.get().await?;
+    let params: Vec<&(dyn ToSql + Sync)> = params.iter().map(|x| x.as_ref() as _).collect();
+    let mut rows = conn.query(sql.as_str(), &params).await?;
+    let has_next_page = rows.len() as i64 > first;
+    rows.truncate(first as usize);
+    let rows: Vec<(String, Row)> = rows
+        .into_iter()
+        .map(|row| {
+            let name: String = row.get("name");
+            (encode_cursor(&encode_position(&sort.value(&row), &name)), row)
+        })
+        .collect();
+    let page_info = PageInfo {
+        has_next_page,
+        has_previous_page: after.is_some(),
+        start_cursor: rows.first().map(|x| x.0.clone()),
+        end_cursor: rows.last().map(|x| x.0.clone()),
+    };
+    Ok((rows, page_info))
+}
+
+// Method to retrieve a page of modules from db
+pub async fn get_modules(
+    db: &Pool,
+    first: Option<i32>,
+    after: Option<String>,
+    filter: Option<ModuleFilter>,
+    order_by: Option<ModuleOrder>,
+) -> ApiResult<PackageConnection> {
+    let mut conditions = Conditions::default();
+    if let Some(filter) = filter {
+        if let Some(owner) = filter.owner {
+            conditions.push("owner", "=", owner);
+        }
+        if let Some(locked) = filter.locked {
+            conditions.push("locked", "=", locked);
+        }
+        if let Some(unlisted) = filter.unlisted {
+            conditions.push("unlisted", "=", unlisted);
+        }
+        if let Some(since) = filter.updated_since {
+            conditions.push("updatedAt", ">=", since);
+        }
+    }
+    let (field, direction) = match order_by {
+        Some(order) => (order.field, order.direction.unwrap_or(OrderDirection::Asc)),
+        None => (ModuleOrderField::Name, OrderDirection::Asc),
+    };
+    let sort = match field {
+        ModuleOrderField::Name => SortKey::Text("name"),
+        ModuleOrderField::CreatedAt => SortKey::Time("createdAt"),
+        ModuleOrderField::UpdatedAt => SortKey::Time("updatedAt"),
+        ModuleOrderField::Downloads => SortKey::Int("downloads"),
+    };
+    let (rows, page_info) =
+        query_page(db, "packages", conditions, sort, direction, first, after).await?;
+    Ok(PackageConnection {
+        edges: rows
+            .iter()
+            .map(|(cursor, row)| PackageEdge {
+                cursor: cursor.clone(),
+                node: package_from_row(row),
+            })
+            .collect(),
+        page_info,
+    })
+}
+
+// Method to retrieve the names of listed modules starting with `prefix`
+pub async fn get_module_names(db: &Pool, prefix: &str, limit: i64) -> ApiResult<Vec<String>> {
+    let conn = db.get().await?;
+    // escape LIKE wildcards in the prefix
+    let pattern = format!(
+        "{}%",
+        prefix.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    );
+    let rows = conn
+        .query(
+            "SELECT name FROM packages WHERE name LIKE $1 AND NOT unlisted AND NOT malicious ORDER BY name LIMIT $2",
+            &[&pattern, &limit],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| row.get("name")).collect())
+}
+
+// Method to retrieve several packages by name
+pub async fn get_packages_by_names(
+    db: &Pool,
+    names: Vec<String>,
+) -> ApiResult<HashMap<String, Package>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query("SELECT * FROM packages WHERE name = ANY($1)", &[&names])
+        .await?;
+    Ok(rows
+        .iter()
+        .map(package_from_row)
+        .map(|x| (x.name.clone(), x))
+        .collect())
 }
 
 // Method to retrieve a package from db
-pub async fn get_package(db: Arc<Client>, name: String) -> Result<Package, String> {
-    let rows = &db
+pub async fn get_package(db: &Pool, name: String) -> ApiResult<Package> {
+    let conn = db.get().await?;
+    let rows = &conn
         .query("SELECT * FROM packages WHERE name = $1", &[&name])
-        .await
-        .unwrap();
-    let _row = first(rows);
-    if let Some(_) = _row {
-        let row = _row.unwrap();
-        let upload_names: Array<String> = row.get(7);
-        Ok(Package {
-            name: row.get(0),
-            normalized_name: row.get(1),
-            owner: row.get(2),
-            description: row.get(3),
-            repository: row.get(4),
-            latest_version: row.get(5),
-            latest_stable_version: row.get(6),
-            package_upload_names: upload_names.iter().cloned().collect(),
-            locked: row.get(8),
-            malicious: row.get(9),
-            unlisted: row.get(10),
-            updated_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(11)),
-            created_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(12)),
-        })
+        .await?;
+    first(rows)
+        .map(package_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// Method to search listed packages, ranking text relevance by popularity.
+// Cursors hold the offset into the ranking.
+pub async fn search_packages(
+    db: &Pool,
+    query: String,
+    first: Option<i32>,
+    after: Option<String>,
+) -> ApiResult<SearchConnection> {
+    let first = i64::from(first.unwrap_or(20).clamp(1, 100));
+    let offset: i64 = match after {
+        Some(cursor) => decode_cursor(&cursor)
+            .and_then(|x| x.strip_prefix("search:").and_then(|x| x.parse().ok()))
+            .ok_or_else(|| ApiError::Validation("Invalid cursor".to_string()))?,
+        None => 0,
+    };
+    let tsquery = prefix_tsquery(&query);
+    let conn = db.get().await?;
+    // fetch one extra row to learn whether there is a next page. Descriptions
+    // are HTML-escaped before highlighting, so that the <mark> tags are the
+    // only markup in snippets.
+    let mut rows = if tsquery.is_empty() {
+        vec![]
     } else {
-        Err("Not found".to_string())
+        conn.query(
+            "SELECT *, (ts_rank_cd(searchVector, query) * (1 + ln(1 + downloads)))::float8 AS rank, \
+             ts_headline('simple', replace(replace(replace(replace(replace(coalesce(description, ''), \
+             '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '''', '&#39;'), \
+             query, 'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=10') AS snippet \
+             FROM packages, to_tsquery('simple', $1) query \
+             WHERE searchVector @@ query AND NOT unlisted AND NOT malicious \
+             ORDER BY rank DESC, name LIMIT $2 OFFSET $3",
+            &[&tsquery, &(first + 1), &offset],
+        )
+        .await?
+    };
+    let has_next_page = rows.len() as i64 > first;
+    rows.truncate(first as usize);
+    let edges: Vec<SearchEdge> = rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| SearchEdge {
+            cursor: encode_cursor(&format!("search:{}", offset + i as i64 + 1)),
+            node: package_from_row(row),
+            rank: row.get("rank"),
+            snippet: row.get("snippet"),
+        })
+        .collect();
+    Ok(SearchConnection {
+        page_info: PageInfo {
+            has_next_page,
+            has_previous_page: offset > 0,
+            start_cursor: edges.first().map(|x| x.cursor.clone()),
+            end_cursor: edges.last().map(|x| x.cursor.clone()),
+        },
+        edges,
+    })
+}
+
+// Method to count a download of a package
+pub async fn count_download(db: &Pool, package: &str) -> ApiResult<()> {
+    let conn = db.get().await?;
+    conn.execute(
+        "UPDATE packages SET downloads = downloads + 1 WHERE name = $1",
+        &[&package],
+    )
+    .await?;
+    Ok(())
+}
+
+// `users` along with the names of the packages each user may manage, derived
+// from `package_owners`. Selected from like a table.
+const USERS: &str = "(SELECT users.*, ARRAY(SELECT package FROM package_owners WHERE package_owners.userName = users.name ORDER BY package_owners.createdAt, package) AS packageNames FROM users) users";
+
+fn public_user_from_row(row: &Row) -> PublicUser {
+    let package_names: Array<String> = row.get("packageNames");
+    PublicUser {
+        name: row.get("name"),
+        normalized_name: row.get("normalizedName"),
+        package_names: package_names.iter().cloned().collect(),
+        created_at: row.get("createdAt"),
     }
 }
 
-// Method to retrieve all users from db
-pub async fn get_users(db: Arc<Client>) -> Result<Vec<PublicUser>, String> {
-    let rows = &db
-        .query("SELECT * FROM users", &[])
-        .await
-        .unwrap();
-    let mut users: Vec<PublicUser> = Vec::new();
-    for i in 0..rows.len() {
-        let row = &rows[i];
-        let package_names: Array<String> = row.get(4);
-        users.push(PublicUser {
-            name: row.get(0),
-            normalized_name: row.get(1),
-            package_names: package_names.iter().cloned().collect(),
-            created_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(5)),
-        });
-    }
-    Ok(users)
-}
-
-// Method to retrieve a user from db using name
-pub async fn get_user_by_name(db: Arc<Client>, name: String) -> Result<PublicUser, String> {
-    let rows = &db
-        .query("SELECT * FROM users WHERE name = $1", &[&name])
-        .await
-        .unwrap();
-    let _row = first(rows);
-    if let Some(_) = _row {
-        let row = _row.unwrap();
-        let package_names: Array<String> = row.get(4);
-        Ok(PublicUser {
-            name: row.get(0),
-            normalized_name: row.get(1),
-            package_names: package_names.iter().cloned().collect(),
-            created_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(5)),
-        })
+// Method to retrieve several users by name
+pub async fn get_users_by_names(
+    db: &Pool,
+    names: Vec<String>,
+) -> ApiResult<HashMap<String, PublicUser>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(format!("SELECT * FROM {} WHERE name = ANY($1)", USERS).as_str(), &[&names])
+        .await?;
+    Ok(rows
+        .iter()
+        .map(public_user_from_row)
+        .map(|x| (x.name.clone(), x))
+        .collect())
+}
+
+// Method to retrieve a page of users from db
+pub async fn get_users(
+    db: &Pool,
+    first: Option<i32>,
+    after: Option<String>,
+    order_by: Option<UserOrder>,
+) -> ApiResult<UserConnection> {
+    let (field, direction) = match order_by {
+        Some(order) => (order.field, order.direction.unwrap_or(OrderDirection::Asc)),
+        None => (UserOrderField::Name, OrderDirection::Asc),
+    };
+    let sort = match field {
+        UserOrderField::Name => SortKey::Text("name"),
+        UserOrderField::CreatedAt => SortKey::Time("createdAt"),
+    };
+    let (rows, page_info) =
+        query_page(db, USERS, Conditions::default(), sort, direction, first, after).await?;
+    Ok(UserConnection {
+        edges: rows
+            .iter()
+            .map(|(cursor, row)| UserEdge {
+                cursor: cursor.clone(),
+                node: public_user_from_row(row),
+            })
+            .collect(),
+        page_info,
+    })
+}
+
+pub async fn get_user_by_name(db: &Pool, name: String) -> ApiResult<PublicUser> {
+    let conn = db.get().await?;
+    let rows = &conn
+        .query(format!("SELECT * FROM {} WHERE name = $1", USERS).as_str(), &[&name])
+        .await?;
+    first(rows)
+        .map(public_user_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// build a User, including private fields, from a `users` row
+fn user_from_row(row: &Row) -> User {
+    let package_names: Array<String> = row.get("packageNames");
+    User {
+        name: row.get("name"),
+        normalized_name: row.get("normalizedName"),
+        api_key: None,
+        api_key_prefix: row
+            .get::<&str, Option<String>>("apiKeyPrefix")
+            .unwrap_or_default(),
+        package_names: package_names.iter().cloned().collect(),
+        admin: row.get("admin"),
+        created_at: row.get("createdAt"),
+    }
+}
+
+// Method to retrieve a user, including private fields, from db using name
+pub async fn get_user(db: &Pool, name: String) -> ApiResult<User> {
+    let conn = db.get().await?;
+    let rows = &conn
+        .query(format!("SELECT * FROM {} WHERE name = $1", USERS).as_str(), &[&name])
+        .await?;
+    first(rows)
+        .map(user_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// Method to resolve an API key or token to its user and what it may do.
+// Account API keys are allowed everything, tokens only their own scopes.
+pub async fn authenticate(db: &Pool, key: &str) -> ApiResult<Option<Auth>> {
+    let conn = db.get().await?;
+    let now = Utc::now();
+    let key_hash = hash_api_key(key);
+    let tokens = &conn
+        .query(
+            "SELECT id, userName, scopes, package, organization FROM tokens WHERE tokenHash = $1 AND revokedAt IS NULL AND (expiresAt IS NULL OR expiresAt > $2)",
+            &[&key_hash, &now],
+        )
+        .await?;
+    let (rows, scopes, package, organization) = if let Some(token) = first(tokens) {
+        conn.execute(
+            "UPDATE tokens SET lastUsedAt = $1 WHERE id = $2",
+            &[&now, &token.get::<usize, String>(0)],
+        )
+        .await?;
+        let scopes: Array<String> = token.get(2);
+        let rows = conn
+            .query(
+                format!("SELECT * FROM {} WHERE name = $1", USERS).as_str(),
+                &[&token.get::<usize, String>(1)],
+            )
+            .await?;
+        let scopes = scopes.iter().filter_map(|x| TokenScope::parse(x)).collect();
+        (rows, scopes, token.get(3), token.get(4))
     } else {
-        Err("Not found".to_string())
+        let rows = conn
+            .query(format!("SELECT * FROM {} WHERE apiKeyHash = $1", USERS).as_str(), &[&key_hash])
+            .await?;
+        let scopes = vec![TokenScope::Publish, TokenScope::ReadPrivate, TokenScope::Admin];
+        (rows, scopes, None, None)
+    };
+    Ok(first(&rows).map(|row| Auth {
+        user: user_from_row(row),
+        scopes,
+        package,
+        organization,
+    }))
+}
+
+// build a Token from a `tokens` row
+fn token_from_row(row: &Row) -> Token {
+    let scopes: Array<String> = row.get("scopes");
+    Token {
+        id: row.get("id"),
+        name: row.get("name"),
+        prefix: row
+            .get::<&str, Option<String>>("prefix")
+            .unwrap_or_default(),
+        scopes: scopes.iter().filter_map(|x| TokenScope::parse(x)).collect(),
+        package: row.get("package"),
+        organization: row.get("organization"),
+        expires_at: row.get("expiresAt"),
+        last_used_at: row.get("lastUsedAt"),
+        created_at: row.get("createdAt"),
     }
 }
 
-// Method to retrieve a user from db using API key
-pub async fn get_user_by_key(db: Arc<Client>, api_key: String) -> Result<User, String> {
-    let rows = &db
-        .query("SELECT * FROM users WHERE apiKey = $1", &[&api_key])
-        .await
-        .unwrap();
-    let _row = first(rows);
-    if let Some(_) = _row {
-        let row = _row.unwrap();
-        let package_names: Array<String> = row.get(4);
-        Ok(User {
-            name: row.get(0),
-            normalized_name: row.get(1),
-            api_key: row.get(3),
-            package_names: package_names.iter().cloned().collect(),
-            created_at: format!("{:?}", row.get::<usize, DateTime<Utc>>(5)),
-        })
+// Method to retrieve the live tokens of a user, leaving out revoked and
+// expired ones
+pub async fn get_tokens(db: &Pool, user: &User) -> ApiResult<Vec<Token>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM tokens WHERE userName = $1 AND revokedAt IS NULL AND (expiresAt IS NULL OR expiresAt > $2) ORDER BY createdAt",
+            &[&user.name, &Utc::now()],
+        )
+        .await?;
+    Ok(rows.iter().map(token_from_row).collect())
+}
+
+// Method to create a token for the user behind `auth`. The token can't reach
+// further than the credential creating it.
+pub async fn create_token(
+    db: &Pool,
+    auth: &Auth,
+    new_token: NewToken,
+) -> ApiResult<NewTokenResult> {
+    if new_token.scopes.is_empty() {
+        return Err(ApiError::Validation("A token needs at least one scope".to_string()));
+    }
+    // the admin scope manages the whole account, which a token limited to
+    // one package must not
+    if new_token.package.is_some() && new_token.scopes.contains(&TokenScope::Admin) {
+        let msg = "Tokens limited to a package can't have the ADMIN scope".to_string();
+        return Err(ApiError::Validation(msg));
+    }
+    if new_token.expires_at.is_some_and(|x| x <= Utc::now()) {
+        return Err(ApiError::Validation("A token can't expire in the past".to_string()));
+    }
+    if !auth.covers(&new_token.scopes, new_token.package.as_deref()) {
+        return Err(ApiError::not_authorized());
+    }
+    let user = &auth.user;
+    if new_token.organization.is_some() && new_token.scopes != [TokenScope::Publish] {
+        let msg = "Organization tokens can only have the PUBLISH scope".to_string();
+        return Err(ApiError::Validation(msg));
+    }
+    // roles are checked in the transaction that stores the token, so a token
+    // can't outlive the role it was created with
+    serializable(db, &(user, new_token), |tx, (user, new_token)| {
+        async move {
+            if let Some(organization) = &new_token.organization {
+                if organization_role(tx, organization, &user.name).await? != Some(PackageRole::Owner) {
+                    return Err(ApiError::not_authorized());
+                }
+            }
+            if let Some(package) = &new_token.package {
+                let allowed = match &new_token.organization {
+                    Some(organization) => !tx
+                        .query(
+                            "SELECT name FROM packages WHERE name = $1 AND owner = $2",
+                            &[package, organization],
+                        )
+                        .await?
+                        .is_empty(),
+                    None => package_role(tx, package, &user.name).await?.is_some(),
+                };
+                if !allowed {
+                    return Err(ApiError::not_authorized());
+                }
+            }
+            let (token, row) = insert_token(tx, &user.name, new_token, false).await?;
+            Ok(NewTokenResult {
+                token,
+                details: token_from_row(&row),
+            })
+        }
+        .boxed()
+    })
+    .await
+}
+
+// store a freshly generated token, returning its plaintext and its row
+async fn insert_token<C: GenericClient>(
+    client: &C,
+    user_name: &str,
+    new_token: &NewToken,
+    login: bool,
+) -> Result<(String, Row), Error> {
+    let id = create_api_key();
+    let token = create_api_key();
+    let scopes: Vec<String> = new_token.scopes.iter().map(|x| x.as_str().to_string()).collect();
+    let row = client
+        .query_one(
+            "INSERT INTO tokens (id, userName, name, tokenHash, prefix, scopes, package, organization, login, expiresAt, createdAt) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) RETURNING *",
+            &[&id, &user_name, &new_token.name, &hash_api_key(&token), &api_key_prefix(&token), &Array::from_vec(scopes, 1), &new_token.package, &new_token.organization, &login, &new_token.expires_at, &Utc::now()],
+        )
+        .await?;
+    Ok((token, row))
+}
+
+// Method to revoke one of the tokens of the user behind `auth`. A credential
+// limited to a package can only revoke tokens limited to the same package.
+pub async fn revoke_token(db: &Pool, auth: &Auth, id: String) -> ApiResult<Token> {
+    let conn = db.get().await?;
+    let rows = &conn
+        .query(
+            "UPDATE tokens SET revokedAt = $1 WHERE id = $2 AND userName = $3 AND revokedAt IS NULL AND ($4::VARCHAR IS NULL OR package = $4) RETURNING *",
+            &[&Utc::now(), &id, &auth.user.name, &auth.package],
+        )
+        .await?;
+    first(rows)
+        .map(token_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// Consecutive failed logins after which an account is throttled
+const MAX_FAILED_LOGINS: i32 = 5;
+
+// Method to log a user in with name and password. Legacy plaintext or outdated
+// hashes are replaced by a hash with the current parameters once the password
+// matches. Accounts with too many consecutive failures are locked for
+// LOGIN_LOCKOUT seconds (900 by default) after the last failed attempt.
+pub async fn login_user(db: &Pool, name: String, password: String) -> ApiResult<User> {
+    let lockout = chrono::Duration::seconds(env_or("LOGIN_LOCKOUT", 900));
+    // count the attempt as failed before the slow password check, so that
+    // parallel guesses can't all get past the limit
+    let attempt = serializable(db, &(name, lockout), |tx, (name, lockout)| {
+        async move {
+            let rows = tx
+                .query(format!("SELECT * FROM {} WHERE name = $1", USERS).as_str(), &[name])
+                .await?;
+            let row = match first(&rows) {
+                Some(row) => row,
+                None => return Ok(None),
+            };
+            let failed_logins: i32 = row.get("failedLogins");
+            let last_failed_login: Option<DateTime<Utc>> = row.get("lastFailedLoginAt");
+            if failed_logins >= MAX_FAILED_LOGINS
+                && last_failed_login.is_some_and(|at| Utc::now() < at + *lockout)
+            {
+                return Err(ApiError::Unauthorized(
+                    "Too many failed login attempts, try again later".to_string(),
+                ));
+            }
+            tx.execute(
+                "UPDATE users SET failedLogins = failedLogins + 1, lastFailedLoginAt = $1 WHERE name = $2",
+                &[&Utc::now(), name],
+            )
+            .await?;
+            Ok(Some((user_from_row(row), row.get::<_, String>("password"))))
+        }
+        .boxed()
+    })
+    .await?;
+    let (user, stored) = match attempt {
+        Some(attempt) => attempt,
+        None => {
+            // spend as long as a real check would, so that unknown names
+            // can't be told apart by timing
+            password::hash(password).await;
+            return Err(ApiError::Unauthorized("Invalid name or password".to_string()));
+        }
+    };
+    if !password::verify(stored.clone(), password.clone()).await {
+        return Err(ApiError::Unauthorized("Invalid name or password".to_string()));
+    }
+    let password_hash = if password::needs_rehash(&stored) {
+        Some(password::hash(password).await)
     } else {
-        Err("Not found".to_string())
+        None
+    };
+
+    // keys are only stored hashed, so hand out a fresh one that expires after
+    // LOGIN_TOKEN_TTL seconds (30 days by default). It manages the account
+    // like the API key does, which is shown only once. The admin scope covers
+    // the account alone, site admin rights come with the user.
+    let new_token = NewToken {
+        name: "login".to_string(),
+        scopes: vec![TokenScope::Publish, TokenScope::ReadPrivate, TokenScope::Admin],
+        package: None,
+        organization: None,
+        expires_at: Some(Utc::now() + chrono::Duration::seconds(env_or("LOGIN_TOKEN_TTL", 2_592_000))),
+    };
+    let limit: i64 = env_or("LOGIN_TOKEN_LIMIT", 10);
+    let args = &(user.name.clone(), new_token, password_hash, limit);
+    let token = serializable(db, args, |tx, (name, new_token, password_hash, limit)| {
+        async move {
+            // the password matched, so the attempt doesn't count
+            tx.execute(
+                "UPDATE users SET failedLogins = 0, password = COALESCE($1, password) WHERE name = $2",
+                &[password_hash, name],
+            )
+            .await?;
+            let (token, _) = insert_token(tx, name, new_token, true).await?;
+            // revoke all but the newest LOGIN_TOKEN_LIMIT login tokens
+            tx.execute(
+                "UPDATE tokens SET revokedAt = $1 WHERE id IN (SELECT id FROM tokens WHERE userName = $2 AND login AND revokedAt IS NULL ORDER BY createdAt DESC OFFSET $3)",
+                &[&Utc::now(), name, limit],
+            )
+            .await?;
+            Ok(token)
+        }
+        .boxed()
+    })
+    .await?;
+    Ok(User {
+        api_key: Some(token),
+        ..user
+    })
+}
+
+// Method to hash the API keys and tokens still stored in plaintext, which
+// are the ones without a prefix
+pub async fn hash_plaintext_keys(db: &Pool) -> ApiResult<()> {
+    let mut conn = db.get().await?;
+    let tx = conn.transaction().await?;
+    for row in tx
+        .query("SELECT name, apiKeyHash FROM users WHERE apiKeyPrefix IS NULL FOR UPDATE", &[])
+        .await?
+    {
+        let key: String = row.get(1);
+        tx.execute(
+            "UPDATE users SET apiKeyHash = $1, apiKeyPrefix = $2 WHERE name = $3",
+            &[&hash_api_key(&key), &api_key_prefix(&key), &row.get::<usize, String>(0)],
+        )
+        .await?;
     }
+    for row in tx
+        .query("SELECT id, tokenHash FROM tokens WHERE prefix IS NULL FOR UPDATE", &[])
+        .await?
+    {
+        let token: String = row.get(1);
+        tx.execute(
+            "UPDATE tokens SET tokenHash = $1, prefix = $2 WHERE id = $3",
+            &[&hash_api_key(&token), &api_key_prefix(&token), &row.get::<usize, String>(0)],
+        )
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+// fail if a user or an organization already goes by `name`, as both share
+// one namespace
+async fn check_name_free(tx: &Transaction<'_>, name: &str) -> ApiResult<()> {
+    let rows = tx
+        .query(
+            "SELECT name FROM users WHERE normalizedName = $1 UNION ALL SELECT name FROM organizations WHERE normalizedName = $1",
+            &[&normalize(name)],
+        )
+        .await?;
+    if !rows.is_empty() {
+        return Err(ApiError::Conflict(format!("The name {} is taken", name)));
+    }
+    Ok(())
 }
 
 // Method to create a user
-pub async fn create_user(db: Arc<Client>, new_user: NewUser) -> Result<User, Error> {
+pub async fn create_user(db: &Pool, new_user: NewUser) -> ApiResult<User> {
     let api_key = create_api_key();
-    let curr_time = Utc::now();
-    let normalized_name = normalize(&new_user.name);
-    let _ = &db
-        .query("INSERT INTO users (name, normalizedName, password, apiKey, packageNames, createdAt) VALUES ($1, $2, $3, $4, $5, $6)", &[&new_user.name, &normalized_name, &new_user.password, &api_key, &Array::<String>::from_vec(vec![], 0), &curr_time])
-        .await?;
-    let name = new_user.name;
+    let password_hash = password::hash(new_user.password.clone()).await;
+    let args = &(new_user.name, password_hash, api_key.clone());
+    let row = serializable(db, args, |tx, (name, password_hash, api_key)| {
+        async move {
+            check_name_free(tx, name).await?;
+            let row = tx
+                .query_one("INSERT INTO users (name, normalizedName, password, apiKeyHash, apiKeyPrefix, createdAt) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *, '{}'::VARCHAR[] AS packageNames", &[name, &normalize(name), password_hash, &hash_api_key(api_key), &api_key_prefix(api_key), &Utc::now()])
+                .await?;
+            Ok(row)
+        }
+        .boxed()
+    })
+    .await?;
     Ok(User {
-        name: name,
-        normalized_name: normalized_name,
-        api_key: api_key,
-        package_names: vec![],
-        created_at: format!("{:?}", Utc::now()),
+        api_key: Some(api_key),
+        ..user_from_row(&row)
+    })
+}
+
+// Method to create a package for the user or one of their organizations, or
+// update the details of one they maintain
+pub async fn publish_package(db: &Pool, user: &User, package: NewPackage) -> ApiResult<Package> {
+    let keywords = package.keywords.clone().map(|x| {
+        let len = x.len() as i32;
+        Array::from_vec(x, len)
+    });
+    serializable(db, &(user, package, keywords), |tx, (user, package, keywords)| {
+        async move {
+            // roles are read inside the transaction, the user passed in may be stale
+            if let Some(role) = package_role(tx, &package.name, &user.name).await? {
+                if role < PackageRole::Maintainer {
+                    return Err(ApiError::not_authorized());
+                }
+                // update table with new details
+                let insert_time = Utc::now();
+                let row = tx
+                    .query_one(
+                    "UPDATE packages SET updatedAt = $1, description = $2, repository = $3, unlisted = $4, keywords = COALESCE($5, keywords) WHERE name = $6 RETURNING *",
+                    &[&insert_time, &package.description, &package.repository, &package.unlisted, keywords, &package.name])
+                    .await?;
+                return Ok(package_from_row(&row));
+            }
+            // check for exiting package
+            if !tx
+                .query("SELECT name FROM packages WHERE name = $1", &[&package.name])
+                .await?
+                .is_empty()
+            {
+                return Err(ApiError::not_authorized());
+            }
+            if !is_valid_package_name(&package.name) {
+                let msg = "Package names are 1 to 40 lowercase letters, digits, _ or -";
+                return Err(ApiError::Validation(msg.to_string()));
+            }
+            if is_reserved_name(&package.name) {
+                return Err(ApiError::Validation(format!("The name {} is reserved", package.name)));
+            }
+            // new packages belong to the author, unless they create it for an
+            // organization they maintain
+            let owner = match &package.organization {
+                Some(organization) => {
+                    match organization_role(tx, organization, &user.name).await? {
+                        Some(role) if role >= PackageRole::Maintainer => organization,
+                        _ => return Err(ApiError::not_authorized()),
+                    }
+                }
+                None => &user.name,
+            };
+            let normalized_name = normalize(&package.name);
+            let insert_time = Utc::now();
+            let row = tx
+                .query_one(
+                    "INSERT INTO packages (name, normalizedName, owner, description, repository, packageUploadNames, keywords, locked, malicious, unlisted, createdAt, updatedAt) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12) RETURNING *",
+                    &[&package.name, &normalized_name, owner, &package.description, &package.repository, &Array::<String>::from_vec(vec![], 0), &keywords.clone().unwrap_or_else(|| Array::from_vec(vec![], 0)), &package.locked, &package.malicious, &package.unlisted, &insert_time, &insert_time]
+                )
+                .await
+                .map_err(|e| match ApiError::from(e) {
+                    ApiError::Conflict(_) => ApiError::Conflict(format!("The name {} is taken", package.name)),
+                    e => e,
+                })?;
+            // the author owns their own new package, organization members
+            // hold their roles through the organization
+            if package.organization.is_none() {
+                tx.execute(
+                    "INSERT INTO package_owners (package, userName, role, createdAt) VALUES ($1, $2, 'owner', $3)",
+                    &[&package.name, &user.name, &insert_time],
+                )
+                .await?;
+            }
+            Ok(package_from_row(&row))
+        }
+        .boxed()
     })
+    .await
+}
+
+// the role of `user` on `package`, if they collaborate on it or are a member
+// of the organization it belongs to. Holding both, the higher one counts.
+async fn package_role<C: GenericClient>(
+    client: &C,
+    package: &str,
+    user: &str,
+) -> ApiResult<Option<PackageRole>> {
+    let rows = client
+        .query(
+            "SELECT role FROM package_owners WHERE package = $1 AND userName = $2 \
+             UNION ALL SELECT organization_members.role FROM packages JOIN organization_members ON organization_members.organization = packages.owner \
+             WHERE packages.name = $1 AND organization_members.userName = $2",
+            &[&package, &user],
+        )
+        .await?;
+    Ok(rows.iter().filter_map(|row| PackageRole::parse(row.get("role"))).max())
+}
+
+// the role a credential acts with on `package`. Organization tokens publish
+// the packages of their organization and nothing else.
+async fn credential_role<C: GenericClient>(
+    client: &C,
+    package: &str,
+    auth: &Auth,
+) -> ApiResult<Option<PackageRole>> {
+    match &auth.organization {
+        Some(organization) => {
+            let rows = client
+                .query(
+                    "SELECT name FROM packages WHERE name = $1 AND owner = $2",
+                    &[&package, organization],
+                )
+                .await?;
+            Ok(first(&rows).map(|_| PackageRole::Publisher))
+        }
+        None => package_role(client, package, &auth.user.name).await,
+    }
+}
+
+
+// fail unless `user` has at least `role` on `package`
+async fn require_role(
+    tx: &Transaction<'_>,
+    package: &str,
+    user: &str,
+    role: PackageRole,
+) -> ApiResult<()> {
+    match package_role(tx, package, user).await? {
+        Some(x) if x >= role => Ok(()),
+        _ => Err(ApiError::not_authorized()),
+    }
+}
+
+// A package keeps at least one owner. Once the account it belongs to is no
+// longer one of them, it passes to the longest-standing owner. Packages of an
+// organization stay with it whoever collaborates on them.
+//
+// `packages.owner` stays next to package_owners because it names the account
+// a package belongs to, which package_owners can't: organizations own their
+// packages without collaborating on them. Only the packages of users need it
+// kept in step with their roles, which every change of roles does through here.
+async fn settle_ownership(tx: &Transaction<'_>, package: &str) -> ApiResult<()> {
+    let organization_owned = !tx
+        .query(
+            "SELECT packages.name FROM packages JOIN organizations ON organizations.name = packages.owner WHERE packages.name = $1",
+            &[&package],
+        )
+        .await?
+        .is_empty();
+    if organization_owned {
+        return Ok(());
+    }
+    let owners: Vec<String> = tx
+        .query(
+            "SELECT userName FROM package_owners WHERE package = $1 AND role = 'owner' ORDER BY createdAt, userName",
+            &[&package],
+        )
+        .await?
+        .iter()
+        .map(|row| row.get("userName"))
+        .collect();
+    if owners.is_empty() {
+        return Err(ApiError::Conflict("A package needs at least one owner".to_string()));
+    }
+    tx.execute(
+        "UPDATE packages SET owner = $2 WHERE name = $1 AND NOT (owner = ANY($3))",
+        &[&package, &owners[0], &owners],
+    )
+    .await?;
+    Ok(())
 }
 
-// TODO: publish packages
-pub async fn publish_package(
-    db: Arc<Client>,
-    package: NewPackage,
-) -> Result<NewPackageResult, Error> {
-    let user_package_rows = &db
+fn collaborator_from_row(row: &Row) -> Collaborator {
+    Collaborator {
+        package: row.get("package"),
+        user_name: row.get("userName"),
+        role: PackageRole::parse(row.get("role")).unwrap_or(PackageRole::Publisher),
+        created_at: row.get("createdAt"),
+    }
+}
+
+fn invite_from_row(row: &Row) -> Invite {
+    Invite {
+        package: row.get("package"),
+        user_name: row.get("userName"),
+        role: PackageRole::parse(row.get("role")).unwrap_or(PackageRole::Publisher),
+        invited_by: row.get("invitedBy"),
+        created_at: row.get("createdAt"),
+    }
+}
+
+// Method to retrieve the collaborators of several packages
+pub async fn get_collaborators_by_packages(
+    db: &Pool,
+    packages: Vec<String>,
+) -> ApiResult<HashMap<String, Vec<Collaborator>>> {
+    let conn = db.get().await?;
+    let rows = conn
         .query(
-            "SELECT * FROM users WHERE apiKey = $1 AND $2 = ANY(packageNames)",
-            &[&package.api_key, &package.name],
+            "SELECT * FROM package_owners WHERE package = ANY($1) ORDER BY createdAt, userName",
+            &[&packages],
         )
         .await?;
-    let rows = &db
-        .query("SELECT * FROM packages WHERE name = $1", &[&package.name])
+    let mut collaborators: HashMap<String, Vec<Collaborator>> =
+        packages.into_iter().map(|x| (x, vec![])).collect();
+    for row in &rows {
+        let collaborator = collaborator_from_row(row);
+        collaborators.entry(collaborator.package.clone()).or_default().push(collaborator);
+    }
+    Ok(collaborators)
+}
+
+// Method to retrieve the pending invitations of a user
+pub async fn get_invites(db: &Pool, user: &User) -> ApiResult<Vec<Invite>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM package_invites WHERE userName = $1 ORDER BY createdAt",
+            &[&user.name],
+        )
         .await?;
-    let normalized_name = normalize(&package.name);
-    let insert_time = Utc::now();
-    if user_package_rows.len() > 0 {
-        // update the package
-        if rows.len() > 0 {
-            // update table with new details
-            let new_package_upload = &db
+    Ok(rows.iter().map(invite_from_row).collect())
+}
+
+// Method for an owner to invite a user to collaborate on a package. Inviting
+// someone again replaces the pending invitation.
+pub async fn invite_collaborator(
+    db: &Pool,
+    user: &User,
+    package: String,
+    invitee: String,
+    role: PackageRole,
+) -> ApiResult<Invite> {
+    serializable(db, &(user, package, invitee, role), |tx, (user, package, invitee, role)| {
+        async move {
+            require_role(tx, package, &user.name, PackageRole::Owner).await?;
+            if tx
+                .query("SELECT name FROM users WHERE name = $1", &[invitee])
+                .await?
+                .is_empty()
+            {
+                return Err(ApiError::NotFound);
+            }
+            let collaborating = !tx
                 .query(
-                "UPDATE packages SET updatedAt = $1, description = $2, repository = $3, unlisted = $4 WHERE name = $2",
-                &[&insert_time, &package.description, &package.repository, &package.unlisted, &package.name])
+                    "SELECT role FROM package_owners WHERE package = $1 AND userName = $2",
+                    &[package, invitee],
+                )
+                .await?
+                .is_empty();
+            if collaborating {
+                return Err(ApiError::Conflict(format!("{} already collaborates on {}", invitee, package)));
+            }
+            let row = tx
+                .query_one(
+                    "INSERT INTO package_invites (package, userName, role, invitedBy, createdAt) VALUES ($1, $2, $3, $4, $5) \
+                     ON CONFLICT (package, userName) DO UPDATE SET role = EXCLUDED.role, invitedBy = EXCLUDED.invitedBy, createdAt = EXCLUDED.createdAt RETURNING *",
+                    &[package, invitee, &role.as_str(), &user.name, &Utc::now()],
+                )
                 .await?;
-            Ok(NewPackageResult {
-                ok: true,
-                msg: "Success".to_owned(),
-            })
-        } else {
-            Ok(NewPackageResult {
-                ok: false,
-                msg: "Not Found".to_owned(),
-            })
+            Ok(invite_from_row(&row))
         }
-    } else {
-        // check for exiting package
-        if rows.len() > 0 {
-            Ok(NewPackageResult {
-                ok: false,
-                msg: "Not Authorized".to_owned(),
-            })
-        } else {
-            // creates a new package entry for the author
-            let normalized_name = normalize(&package.name);
-            let insert_time = Utc::now();
-            let new_package_upload = &db
+        .boxed()
+    })
+    .await
+}
+
+// Method for a user to accept their invitation to collaborate on a package
+pub async fn accept_invite(db: &Pool, user: &User, package: String) -> ApiResult<Collaborator> {
+    serializable(db, &(user, package), |tx, (user, package)| {
+        async move {
+            let rows = tx
                 .query(
-                    "INSERT INTO packages (name, normalizedName, owner, description, repository, packageUploadNames, locked, malicious, unlisted, createdAt, updatedAt) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
-                    &[&package.name, &normalized_name, &user_package_rows.first().unwrap().get::<usize, String>(0), &package.description, &package.repository, &Array::<String>::from_vec(vec![], 0), &package.locked, &package.malicious, &package.unlisted, &insert_time, &insert_time]
+                    "DELETE FROM package_invites WHERE package = $1 AND userName = $2 RETURNING role",
+                    &[package, &user.name],
                 )
                 .await?;
-            // update user and push the new package name
-            let mut package_names: Vec<String> = user_package_rows.first().unwrap().get::<usize, Array<String>>(4).iter().cloned().collect();
-            package_names.push(package.name);
-            let new_package_upload = &db
+            let role: String = first(&rows).ok_or(ApiError::NotFound)?.get("role");
+            let row = tx
+                .query_one(
+                    "INSERT INTO package_owners (package, userName, role, createdAt) VALUES ($1, $2, $3, $4) RETURNING *",
+                    &[package, &user.name, &role, &Utc::now()],
+                )
+                .await?;
+            Ok(collaborator_from_row(&row))
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method for an owner to change the role of a collaborator
+pub async fn change_collaborator_role(
+    db: &Pool,
+    user: &User,
+    package: String,
+    member: String,
+    role: PackageRole,
+) -> ApiResult<Collaborator> {
+    serializable(db, &(user, package, member, role), |tx, (user, package, member, role)| {
+        async move {
+            require_role(tx, package, &user.name, PackageRole::Owner).await?;
+            let rows = tx
                 .query(
-                "UPDATE users SET packageNames = $1 WHERE name = $2",
-                &[&Array::<String>::from_vec(package_names.clone(), package_names.len() as i32), &user_package_rows.first().unwrap().get::<usize, String>(0)])
+                    "UPDATE package_owners SET role = $3 WHERE package = $1 AND userName = $2 RETURNING *",
+                    &[package, member, &role.as_str()],
+                )
                 .await?;
-            Ok(NewPackageResult {
-                ok: true,
-                msg: "Success".to_owned(),
-            })
+            let collaborator = first(&rows).map(collaborator_from_row).ok_or(ApiError::NotFound)?;
+            settle_ownership(tx, package).await?;
+            Ok(collaborator)
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method for an owner to remove a collaborator or a pending invitation, or
+// for a collaborator to leave a package
+pub async fn remove_collaborator(
+    db: &Pool,
+    user: &User,
+    package: String,
+    member: String,
+) -> ApiResult<bool> {
+    serializable(db, &(user, package, member), |tx, (user, package, member)| {
+        async move {
+            if *member != user.name {
+                require_role(tx, package, &user.name, PackageRole::Owner).await?;
+            }
+            let removed = tx
+                .execute(
+                    "DELETE FROM package_owners WHERE package = $1 AND userName = $2",
+                    &[package, member],
+                )
+                .await?
+                + tx
+                    .execute(
+                        "DELETE FROM package_invites WHERE package = $1 AND userName = $2",
+                        &[package, member],
+                    )
+                    .await?;
+            if removed == 0 {
+                return Err(ApiError::NotFound);
+            }
+            settle_ownership(tx, package).await?;
+            Ok(true)
         }
+        .boxed()
+    })
+    .await
+}
+
+// organizations with the names of the packages they own
+const ORGANIZATIONS: &str = "(SELECT organizations.*, ARRAY(SELECT name FROM packages WHERE packages.owner = organizations.name ORDER BY packages.createdAt, name) AS packageNames FROM organizations) organizations";
+
+fn organization_from_row(row: &Row) -> Organization {
+    let package_names: Array<String> = row.get("packageNames");
+    Organization {
+        name: row.get("name"),
+        normalized_name: row.get("normalizedName"),
+        description: row.get("description"),
+        package_names: package_names.iter().cloned().collect(),
+        created_at: row.get("createdAt"),
+    }
+}
+
+fn member_from_row(row: &Row) -> OrganizationMember {
+    OrganizationMember {
+        organization: row.get("organization"),
+        user_name: row.get("userName"),
+        role: PackageRole::parse(row.get("role")).unwrap_or(PackageRole::Publisher),
+        created_at: row.get("createdAt"),
     }
 }
 
+// Method to retrieve an organization by name
+pub async fn get_organization(db: &Pool, name: String) -> ApiResult<Organization> {
+    let conn = db.get().await?;
+    let rows = &conn
+        .query(format!("SELECT * FROM {} WHERE name = $1", ORGANIZATIONS).as_str(), &[&name])
+        .await?;
+    first(rows)
+        .map(organization_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// Method to retrieve several organizations by name
+pub async fn get_organizations_by_names(
+    db: &Pool,
+    names: Vec<String>,
+) -> ApiResult<HashMap<String, Organization>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(format!("SELECT * FROM {} WHERE name = ANY($1)", ORGANIZATIONS).as_str(), &[&names])
+        .await?;
+    Ok(rows
+        .iter()
+        .map(organization_from_row)
+        .map(|x| (x.name.clone(), x))
+        .collect())
+}
+
+// Method to retrieve the members of several organizations
+pub async fn get_members_by_organizations(
+    db: &Pool,
+    organizations: Vec<String>,
+) -> ApiResult<HashMap<String, Vec<OrganizationMember>>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM organization_members WHERE organization = ANY($1) ORDER BY createdAt, userName",
+            &[&organizations],
+        )
+        .await?;
+    let mut members: HashMap<String, Vec<OrganizationMember>> =
+        organizations.into_iter().map(|x| (x, vec![])).collect();
+    for row in &rows {
+        let member = member_from_row(row);
+        members.entry(member.organization.clone()).or_default().push(member);
+    }
+    Ok(members)
+}
+
+// the role of `user` in `organization`, if they are a member
+async fn organization_role<C: GenericClient>(
+    client: &C,
+    organization: &str,
+    user: &str,
+) -> ApiResult<Option<PackageRole>> {
+    let rows = client
+        .query(
+            "SELECT role FROM organization_members WHERE organization = $1 AND userName = $2",
+            &[&organization, &user],
+        )
+        .await?;
+    Ok(first(&rows).and_then(|row| PackageRole::parse(row.get("role"))))
+}
+
+// fail unless `user` owns `organization`
+async fn require_organization_owner(
+    tx: &Transaction<'_>,
+    organization: &str,
+    user: &str,
+) -> ApiResult<()> {
+    match organization_role(tx, organization, user).await? {
+        Some(PackageRole::Owner) => Ok(()),
+        _ => Err(ApiError::not_authorized()),
+    }
+}
+
+// An organization keeps at least one owner, and only owners hold its tokens
+async fn settle_organization(tx: &Transaction<'_>, organization: &str) -> ApiResult<()> {
+    let owners = tx
+        .query(
+            "SELECT userName FROM organization_members WHERE organization = $1 AND role = 'owner'",
+            &[&organization],
+        )
+        .await?;
+    if owners.is_empty() {
+        let msg = "An organization needs at least one owner".to_string();
+        return Err(ApiError::Conflict(msg));
+    }
+    tx.execute(
+        "UPDATE tokens SET revokedAt = $2 WHERE organization = $1 AND revokedAt IS NULL \
+         AND userName NOT IN (SELECT userName FROM organization_members WHERE organization = $1 AND role = 'owner')",
+        &[&organization, &Utc::now()],
+    )
+    .await?;
+    Ok(())
+}
+
+// Method to create an organization owned by the user
+pub async fn create_organization(
+    db: &Pool,
+    user: &User,
+    name: String,
+    description: String,
+) -> ApiResult<Organization> {
+    serializable(db, &(user, name, description), |tx, (user, name, description)| {
+        async move {
+            check_name_free(tx, name).await?;
+            let insert_time = Utc::now();
+            let row = tx
+                .query_one(
+                    "INSERT INTO organizations (name, normalizedName, description, createdAt) VALUES ($1, $2, $3, $4) RETURNING *, '{}'::VARCHAR[] AS packageNames",
+                    &[name, &normalize(name), description, &insert_time],
+                )
+                .await?;
+            tx.execute(
+                "INSERT INTO organization_members (organization, userName, role, createdAt) VALUES ($1, $2, 'owner', $3)",
+                &[name, &user.name, &insert_time],
+            )
+            .await?;
+            Ok(organization_from_row(&row))
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method for an owner to add a user to an organization
+pub async fn add_organization_member(
+    db: &Pool,
+    user: &User,
+    organization: String,
+    member: String,
+    role: PackageRole,
+) -> ApiResult<OrganizationMember> {
+    let args = &(user, organization, member, role);
+    serializable(db, args, |tx, (user, organization, member, role)| {
+        async move {
+            require_organization_owner(tx, organization, &user.name).await?;
+            if tx
+                .query("SELECT name FROM users WHERE name = $1", &[member])
+                .await?
+                .is_empty()
+            {
+                return Err(ApiError::NotFound);
+            }
+            let row = tx
+                .query_one(
+                    "INSERT INTO organization_members (organization, userName, role, createdAt) VALUES ($1, $2, $3, $4) RETURNING *",
+                    &[organization, member, &role.as_str(), &Utc::now()],
+                )
+                .await
+                .map_err(|e| match ApiError::from(e) {
+                    ApiError::Conflict(_) => {
+                        ApiError::Conflict(format!("{} is already a member of {}", member, organization))
+                    }
+                    e => e,
+                })?;
+            Ok(member_from_row(&row))
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method for an owner to change the role of a member of an organization
+pub async fn change_organization_member_role(
+    db: &Pool,
+    user: &User,
+    organization: String,
+    member: String,
+    role: PackageRole,
+) -> ApiResult<OrganizationMember> {
+    let args = &(user, organization, member, role);
+    serializable(db, args, |tx, (user, organization, member, role)| {
+        async move {
+            require_organization_owner(tx, organization, &user.name).await?;
+            let rows = tx
+                .query(
+                    "UPDATE organization_members SET role = $3 WHERE organization = $1 AND userName = $2 RETURNING *",
+                    &[organization, member, &role.as_str()],
+                )
+                .await?;
+            let member = first(&rows).map(member_from_row).ok_or(ApiError::NotFound)?;
+            settle_organization(tx, organization).await?;
+            Ok(member)
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method for an owner to remove a member from an organization, or for a
+// member to leave it
+pub async fn remove_organization_member(
+    db: &Pool,
+    user: &User,
+    organization: String,
+    member: String,
+) -> ApiResult<bool> {
+    serializable(db, &(user, organization, member), |tx, (user, organization, member)| {
+        async move {
+            if *member != user.name {
+                require_organization_owner(tx, organization, &user.name).await?;
+            }
+            let removed = tx
+                .execute(
+                    "DELETE FROM organization_members WHERE organization = $1 AND userName = $2",
+                    &[organization, member],
+                )
+                .await?;
+            if removed == 0 {
+                return Err(ApiError::NotFound);
+            }
+            settle_organization(tx, organization).await?;
+            Ok(true)
+        }
+        .boxed()
+    })
+    .await
+}
+
 
-#[derive(Debug, Deserialize, Serialize, FromSql)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Files {
     pub in_manifest: String,
     pub tx_id: String,
 }
 
+// Build the typed file manifest of a version, ordered by path
+fn package_files(files: HashMap<String, Files>) -> Vec<PackageFile> {
+    let mut files: Vec<PackageFile> = files
+        .into_iter()
+        .map(|(path, file)| PackageFile {
+            path,
+            in_manifest: file.in_manifest,
+            tx_id: file.tx_id,
+        })
+        .collect();
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files
+}
+
+fn version_from_row(row: &Row) -> PackageVersion {
+    // rows written before the manifest format was settled may not parse
+    let files = row
+        .try_get::<_, Option<Json<HashMap<String, Files>>>>("files")
+        .ok()
+        .flatten()
+        .map(|files| files.0)
+        .unwrap_or_default();
+    PackageVersion {
+        name: row.get("name"),
+        package: row.get("package"),
+        version: row.get("version"),
+        entry: row.get::<_, Option<String>>("entry").unwrap_or_default(),
+        prefix: row.get::<_, Option<String>>("prefix").unwrap_or_default(),
+        malicious: row.get::<_, Option<bool>>("malicious").unwrap_or(false),
+        stable: row.get("stable"),
+        yanked: row.get("yanked"),
+        files: package_files(files),
+        created_at: row.get("createdAt"),
+    }
+}
+
+// Method to retrieve all versions of a package, newest first
+pub async fn get_package_versions(db: &Pool, package: &str) -> ApiResult<Vec<PackageVersion>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM \"package-uploads\" WHERE package = $1 ORDER BY createdAt DESC",
+            &[&package],
+        )
+        .await?;
+    Ok(rows.iter().map(version_from_row).collect())
+}
+
+// Method to retrieve the versions of several packages, newest first
+pub async fn get_versions_by_packages(
+    db: &Pool,
+    packages: Vec<String>,
+) -> ApiResult<HashMap<String, Vec<PackageVersion>>> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM \"package-uploads\" WHERE package = ANY($1) ORDER BY createdAt DESC",
+            &[&packages],
+        )
+        .await?;
+    let mut versions: HashMap<String, Vec<PackageVersion>> =
+        packages.into_iter().map(|x| (x, vec![])).collect();
+    for row in &rows {
+        let version = version_from_row(row);
+        versions.entry(version.package.clone()).or_default().push(version);
+    }
+    Ok(versions)
+}
+
+// Method to retrieve a single version of a package
+pub async fn get_package_version(
+    db: &Pool,
+    package: String,
+    version: String,
+) -> ApiResult<PackageVersion> {
+    let conn = db.get().await?;
+    let rows = &conn
+        .query(
+            "SELECT * FROM \"package-uploads\" WHERE package = $1 AND version = $2",
+            &[&package, &version],
+        )
+        .await?;
+    first(rows)
+        .map(version_from_row)
+        .ok_or(ApiError::NotFound)
+}
+
+// The highest version overall and the highest stable one, out of the versions
+// ranges resolve to, so neither yanked nor malicious ones. Prereleases and
+// versions published with `stable: false` are never the latest stable.
+// Versions that predate semver validation and do not parse are ignored.
+fn latest_versions(versions: &[PackageVersion]) -> (Option<String>, Option<String>) {
+    let versions: Vec<(Version, &PackageVersion)> = versions
+        .iter()
+        .filter(|x| !x.yanked && !x.malicious)
+        .filter_map(|x| parse_version(&x.version).map(|v| (v, x)))
+        .collect();
+    let highest = |stable_only: bool| {
+        versions
+            .iter()
+            .filter(|(v, x)| !stable_only || (x.stable && v.pre.is_empty()))
+            .max_by(|a, b| a.0.cmp_precedence(&b.0))
+            .map(|(_, x)| x.version.clone())
+    };
+    (highest(false), highest(true))
+}
+
+// Recompute the latest versions of a package from its uploads
+async fn update_latest_versions(tx: &Transaction<'_>, package: &str) -> Result<(), Error> {
+    let versions: Vec<PackageVersion> = tx
+        .query("SELECT * FROM \"package-uploads\" WHERE package = $1", &[&package])
+        .await?
+        .iter()
+        .map(version_from_row)
+        .collect();
+    let (latest, latest_stable) = latest_versions(&versions);
+    tx.execute(
+        "UPDATE packages SET latestVersion = $1, latestStableVersion = $2 WHERE name = $3",
+        &[&latest, &latest_stable, &package],
+    )
+    .await?;
+    Ok(())
+}
+
+// the longest version, prefix and entry "package-uploads" stores
+const MAX_VERSION_LEN: usize = 20;
+const MAX_PREFIX_LEN: usize = 20;
+const MAX_ENTRY_LEN: usize = 60;
+
+// fail unless the credential may publish `upload` as a new version of an
+// existing package
+async fn check_new_version<C: GenericClient>(
+    client: &C,
+    auth: &Auth,
+    upload: &NewPackageUpload,
+) -> ApiResult<()> {
+    let (package, version) = (&upload.name, &upload.version);
+    let parsed = parse_version(version)
+        .ok_or_else(|| ApiError::Validation(format!("{} is not a semantic version", version)))?;
+    if version.len() > MAX_VERSION_LEN {
+        let msg = format!("Versions are at most {} characters long", MAX_VERSION_LEN);
+        return Err(ApiError::Validation(msg));
+    }
+    if upload.prefix.chars().count() > MAX_PREFIX_LEN {
+        let msg = format!("Prefixes are at most {} characters long", MAX_PREFIX_LEN);
+        return Err(ApiError::Validation(msg));
+    }
+    if upload.entry.chars().count() > MAX_ENTRY_LEN {
+        let msg = format!("Entries are at most {} characters long", MAX_ENTRY_LEN);
+        return Err(ApiError::Validation(msg));
+    }
+    if client
+        .query("SELECT name FROM packages WHERE name = $1", &[&package])
+        .await?
+        .is_empty()
+    {
+        return Err(ApiError::NotFound);
+    }
+    if credential_role(client, package, auth).await?.is_none() {
+        return Err(ApiError::not_authorized());
+    }
+    // yanked versions count too, a yanked version can not be published again
+    let taken = client
+        .query(
+            "SELECT version FROM \"package-uploads\" WHERE package = $1",
+            &[&package],
+        )
+        .await?
+        .iter()
+        .filter_map(|row| parse_version(row.get("version")))
+        .any(|v| v.cmp_precedence(&parsed) == Ordering::Equal);
+    if taken {
+        return Err(ApiError::Conflict(format!("Version {} already exists", version)));
+    }
+    Ok(())
+}
+
+// fail unless the manifest keys `paths` hold the entry of `upload`
+fn check_files(upload: &NewPackageUpload, paths: &[String]) -> ApiResult<()> {
+    if paths.is_empty() {
+        return Err(ApiError::Validation("An upload needs at least one file".to_string()));
+    }
+    if !paths.contains(&manifest_path(&upload.prefix, &upload.entry)) {
+        let msg = format!("The entry {} is not among the uploaded files", upload.entry);
+        return Err(ApiError::Validation(msg));
+    }
+    Ok(())
+}
+
+// Method to check that a version may be published before its files are
+// pushed to storage, which can't be undone. `paths` are the manifest keys the
+// files will be stored under.
+pub async fn check_upload(
+    db: &Pool,
+    auth: &Auth,
+    upload: &NewPackageUpload,
+    paths: &[String],
+) -> ApiResult<()> {
+    let conn = db.get().await?;
+    check_new_version(&*conn, auth, upload).await?;
+    check_files(upload, paths)
+}
 
-// TODO: implement upload creation
+// Method to record a new version of an existing package
 pub async fn create_package_uploads(
-    db: Arc<Client>,
+    db: &Pool,
+    auth: &Auth,
     package: NewPackageUpload,
-    files: Files,
-    prefix: String,
-) -> Result<NewPackageResult, Error> {
-    if !&package.upload {
-        Ok(NewPackageResult {
-            ok: true,
-            msg: "Success".to_owned(),
-        })
-    } else {
-        let rows = &db
-            .query("SELECT * FROM packages WHERE name = $1", &[&package.name])
-            .await?;
-        if rows.len() > 0 {
-            // TODO: insert new package into DB
+    files: HashMap<String, Files>,
+) -> ApiResult<PackageVersion> {
+    serializable(db, &(auth, package, Json(files)), |tx, (auth, package, files)| {
+        async move {
+            // lock the package so concurrent uploads recompute its latest versions in turn
+            tx.query("SELECT name FROM packages WHERE name = $1 FOR UPDATE", &[&package.name])
+                .await?;
+            check_new_version(tx, auth, package).await?;
+
             let new_package_name = format!("{}@{}", &package.name, &package.version);
             let insert_time = Utc::now();
-            let new_package_upload = &db
-             .query(
-                  "INSERT INTO 'package-uploads' (name, package, entry, version, prefix, files, createdAt) VALUES ($1, $2, $3, $4, $5, $6, $7)",
-                  &[&new_package_name, &package.name, &package.entry, &package.version, &prefix, &Json::<Files>(files), &insert_time]
-              )
-             .await?;
-            Ok(NewPackageResult {
-                ok: true,
-                msg: "Success".to_owned(),
-            })
-        } else {
-            Ok(NewPackageResult {
-                ok: false,
-                msg: "Not found".to_owned(),
-            })
+            let row = tx.query_one(
+                "INSERT INTO \"package-uploads\" (name, package, entry, version, prefix, malicious, stable, files, createdAt) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *",
+                &[&new_package_name, &package.name, &package.entry, &package.version, &package.prefix, &false, &package.stable, files, &insert_time],
+            )
+            .await?;
+            // register the upload on its package
+            tx.execute(
+                "UPDATE packages SET packageUploadNames = array_append(packageUploadNames, $1), updatedAt = $2 WHERE name = $3",
+                &[&new_package_name, &insert_time, &package.name],
+            )
+            .await?;
+            update_latest_versions(tx, &package.name).await?;
+            Ok(version_from_row(&row))
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method to yank or restore a version of a package
+pub async fn yank_version(
+    db: &Pool,
+    user: &User,
+    package: String,
+    version: String,
+    yanked: bool,
+) -> ApiResult<PackageVersion> {
+    serializable(db, &(user, package, version, yanked), |tx, (user, package, version, yanked)| {
+        async move {
+            // lock the package like uploads do before recomputing its latest versions
+            tx.query("SELECT name FROM packages WHERE name = $1 FOR UPDATE", &[package])
+                .await?;
+            require_role(tx, package, &user.name, PackageRole::Maintainer).await?;
+            let rows = &tx
+                .query(
+                    "UPDATE \"package-uploads\" SET yanked = $1 WHERE package = $2 AND version = $3 RETURNING *",
+                    &[yanked, package, version],
+                )
+                .await?;
+            let version = first(rows)
+                .map(version_from_row)
+                .ok_or(ApiError::NotFound)?;
+            update_latest_versions(tx, package).await?;
+            Ok(version)
+        }
+        .boxed()
+    })
+    .await
+}
+
+// Method to find the highest non-yanked version of a package matching `range`
+pub async fn resolve_version(
+    db: &Pool,
+    package: String,
+    range: &VersionReq,
+) -> ApiResult<PackageVersion> {
+    let conn = db.get().await?;
+    let rows = conn
+        .query(
+            "SELECT * FROM \"package-uploads\" WHERE package = $1 AND NOT yanked AND malicious IS NOT TRUE",
+            &[&package],
+        )
+        .await?;
+    rows.iter()
+        .filter_map(|row| {
+            let version = parse_version(row.get("version"))?;
+            if range.matches(&version) {
+                Some((version, row))
+            } else {
+                None
+            }
+        })
+        .max_by(|a, b| a.0.cmp_precedence(&b.0))
+        .map(|(_, row)| version_from_row(row))
+        .ok_or(ApiError::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str, stable: bool) -> PackageVersion {
+        PackageVersion {
+            name: format!("eggs@{}", version),
+            package: "eggs".to_string(),
+            version: version.to_string(),
+            entry: "/mod.ts".to_string(),
+            prefix: String::new(),
+            malicious: false,
+            stable,
+            yanked: false,
+            files: vec![],
+            created_at: Utc::now(),
+        }
+    }
+
+    fn some(version: &str) -> Option<String> {
+        Some(version.to_string())
+    }
+
+    #[test]
+    fn latest_versions_compare_by_semver() {
+        let versions = [version("v1.9.0", true), version("v1.10.0", true), version("1.2.0", true)];
+        assert_eq!(latest_versions(&versions), (some("v1.10.0"), some("v1.10.0")));
+    }
+
+    #[test]
+    fn latest_versions_keep_prereleases_from_stable() {
+        let versions = [version("1.0.0", true), version("1.1.0-beta.1", true)];
+        assert_eq!(latest_versions(&versions), (some("1.1.0-beta.1"), some("1.0.0")));
+    }
+
+    #[test]
+    fn latest_versions_keep_unstable_versions_from_stable() {
+        let versions = [version("1.0.0", true), version("1.1.0", false)];
+        assert_eq!(latest_versions(&versions), (some("1.1.0"), some("1.0.0")));
+        assert_eq!(latest_versions(&versions[1..]), (some("1.1.0"), None));
+    }
+
+    #[test]
+    fn latest_versions_skip_yanked_and_malicious() {
+        let mut yanked = version("1.2.0", true);
+        yanked.yanked = true;
+        let mut malicious = version("1.1.0", true);
+        malicious.malicious = true;
+        let versions = [version("1.0.0", true), yanked, malicious];
+        assert_eq!(latest_versions(&versions), (some("1.0.0"), some("1.0.0")));
+        assert_eq!(latest_versions(&versions[1..]), (None, None));
+    }
+
+    #[test]
+    fn latest_versions_ignore_malformed() {
+        let versions = [version("0.1.0", true), version("latest", true)];
+        assert_eq!(latest_versions(&versions), (some("0.1.0"), some("0.1.0")));
+    }
+
+    fn upload(entry: &str, prefix: &str) -> NewPackageUpload {
+        NewPackageUpload {
+            name: "eggs".to_string(),
+            version: "1.0.0".to_string(),
+            entry: entry.to_string(),
+            prefix: prefix.to_string(),
+            stable: true,
         }
     }
+
+    #[test]
+    fn check_files_finds_entry_below_prefix() {
+        let paths = vec!["/mod.ts".to_string(), "/src/lib.ts".to_string()];
+        assert!(check_files(&upload("/mod.ts", ""), &paths).is_ok());
+        assert!(check_files(&upload("lib.ts", "src"), &paths).is_ok());
+        assert!(check_files(&upload("/lib.ts", ""), &paths).is_err());
+    }
+
+    #[test]
+    fn check_files_rejects_empty_uploads() {
+        assert!(check_files(&upload("/mod.ts", ""), &[]).is_err());
+    }
 }
@@ -0,0 +1,197 @@
+//! Errors of the data layer, with the machine-readable code clients see them
+//! as: `extensions.code` in GraphQL errors and `code` in REST error bodies
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use juniper::{graphql_value, FieldError, IntoFieldError, ScalarValue};
+use serde_json::json;
+use std::fmt;
+use tokio_postgres::error::SqlState;
+
+use crate::db::PoolError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ApiError {
+    NotFound,
+    Unauthorized(String),
+    Conflict(String),
+    Validation(String),
+    // a serializable transaction lost against a concurrent one. Retried by
+    // `db::serializable`, reported as CONFLICT once the retries run out.
+    Serialization,
+    // details are logged where the error happens, clients only learn that
+    // something went wrong
+    Internal,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+impl ApiError {
+    pub fn not_authorized() -> ApiError {
+        ApiError::Unauthorized("Not Authorized".to_string())
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound => "NOT_FOUND",
+            ApiError::Unauthorized(_) => "UNAUTHORIZED",
+            ApiError::Conflict(_) | ApiError::Serialization => "CONFLICT",
+            ApiError::Validation(_) => "VALIDATION",
+            ApiError::Internal => "INTERNAL",
+        }
+    }
+
+    // the JSON body of a REST error response
+    pub fn body(&self) -> serde_json::Value {
+        json!({ "code": self.code(), "message": self.to_string() })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NotFound => write!(f, "Not found"),
+            ApiError::Unauthorized(msg) | ApiError::Conflict(msg) | ApiError::Validation(msg) => {
+                write!(f, "{}", msg)
+            }
+            ApiError::Serialization => write!(f, "Conflicting concurrent update, try again"),
+            ApiError::Internal => write!(f, "Internal server error"),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+// the error clients see for a statement that failed with `code`, if it is one
+// they can act on
+fn from_sql_state(code: &SqlState) -> Option<ApiError> {
+    if *code == SqlState::UNIQUE_VIOLATION {
+        Some(ApiError::Conflict("Already exists".to_string()))
+    } else if *code == SqlState::T_R_SERIALIZATION_FAILURE
+        || *code == SqlState::T_R_DEADLOCK_DETECTED
+    {
+        Some(ApiError::Serialization)
+    } else if *code == SqlState::STRING_DATA_RIGHT_TRUNCATION {
+        Some(ApiError::Validation("Value too long".to_string()))
+    } else {
+        None
+    }
+}
+
+impl From<tokio_postgres::Error> for ApiError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        e.code().and_then(from_sql_state).unwrap_or_else(|| {
+            eprintln!("database error: {}", e);
+            ApiError::Internal
+        })
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(e: PoolError) -> Self {
+        match e {
+            bb8::RunError::User(e) => e.into(),
+            bb8::RunError::TimedOut => {
+                eprintln!("database error: timed out waiting for a connection");
+                ApiError::Internal
+            }
+        }
+    }
+}
+
+impl<S: ScalarValue> IntoFieldError<S> for ApiError {
+    fn into_field_error(self) -> FieldError<S> {
+        let code = self.code();
+        FieldError::new(self, graphql_value!({ "code": code }))
+    }
+}
+
+// Authenticated callers that lack a permission get 403, see `main::authenticate`
+// for the 401 of missing or unknown credentials
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) => StatusCode::FORBIDDEN,
+            ApiError::Conflict(_) | ApiError::Serialization => StatusCode::CONFLICT,
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.body())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::DefaultScalarValue;
+
+    #[test]
+    fn sql_states() {
+        let conflict = ApiError::Conflict("Already exists".to_string());
+        assert_eq!(from_sql_state(&SqlState::UNIQUE_VIOLATION), Some(conflict));
+        let serialization = Some(ApiError::Serialization);
+        assert_eq!(
+            from_sql_state(&SqlState::T_R_SERIALIZATION_FAILURE),
+            serialization
+        );
+        assert_eq!(
+            from_sql_state(&SqlState::T_R_DEADLOCK_DETECTED),
+            serialization
+        );
+        let too_long = ApiError::Validation("Value too long".to_string());
+        assert_eq!(
+            from_sql_state(&SqlState::STRING_DATA_RIGHT_TRUNCATION),
+            Some(too_long)
+        );
+    }
+
+    #[test]
+    fn unexpected_sql_states() {
+        assert_eq!(from_sql_state(&SqlState::FOREIGN_KEY_VIOLATION), None);
+        assert_eq!(from_sql_state(&SqlState::UNDEFINED_TABLE), None);
+    }
+
+    #[test]
+    fn codes_and_statuses() {
+        let cases = [
+            (ApiError::NotFound, "NOT_FOUND", StatusCode::NOT_FOUND),
+            (
+                ApiError::not_authorized(),
+                "UNAUTHORIZED",
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                ApiError::Conflict("x".to_string()),
+                "CONFLICT",
+                StatusCode::CONFLICT,
+            ),
+            (ApiError::Serialization, "CONFLICT", StatusCode::CONFLICT),
+            (
+                ApiError::Validation("x".to_string()),
+                "VALIDATION",
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                ApiError::Internal,
+                "INTERNAL",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (e, code, status) in cases.iter() {
+            assert_eq!(e.code(), *code);
+            assert_eq!(e.status_code(), *status);
+            assert_eq!(e.body(), json!({ "code": code, "message": e.to_string() }));
+        }
+    }
+
+    #[test]
+    fn field_error_carries_code() {
+        let e: FieldError<DefaultScalarValue> =
+            ApiError::Validation("Bad".to_string()).into_field_error();
+        assert_eq!(e.message(), "Bad");
+        assert_eq!(e.extensions(), &graphql_value!({ "code": "VALIDATION" }));
+    }
+}
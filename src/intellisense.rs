@@ -0,0 +1,127 @@
+// Deno import intellisense: lets editors complete package names, versions and
+// file paths of imports from this registry. See
+// https://deno.land/manual/language_server/imports#registry-completions
+use actix_web::{web, Error, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::db;
+use crate::error::ApiError;
+use crate::serve::import_path;
+use crate::utils::parse_version;
+use crate::AppState;
+
+// completions beyond this many are cut off and marked incomplete
+const MAX_ITEMS: usize = 100;
+
+#[derive(Deserialize)]
+pub struct Completion {
+    #[serde(default)]
+    q: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Items {
+    items: Vec<String>,
+    is_incomplete: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preselect: Option<String>,
+}
+
+impl Items {
+    fn new(mut items: Vec<String>, preselect: Option<String>) -> Items {
+        let is_incomplete = items.len() > MAX_ITEMS;
+        items.truncate(MAX_ITEMS);
+        Items {
+            items,
+            is_incomplete,
+            preselect,
+        }
+    }
+}
+
+// The registry configuration Deno discovers through the well-known URL
+pub async fn config() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "version": 2,
+        "registries": [{
+            "schema": "/:module([^@/]+)@:version?/:path*",
+            "variables": [
+                {
+                    "key": "module",
+                    "url": "/intellisense/modules?q=${module}"
+                },
+                {
+                    "key": "version",
+                    "url": "/intellisense/modules/${module}/versions?q=${version}"
+                },
+                {
+                    "key": "path",
+                    "url": "/intellisense/modules/${module}/${{version}}/paths?q=${path}"
+                }
+            ]
+        }]
+    }))
+}
+
+// Names of the listed packages starting with the query
+pub async fn modules(
+    st: web::Data<AppState>,
+    query: web::Query<Completion>,
+) -> Result<HttpResponse, Error> {
+    // one more than fits to tell whether the list is complete
+    let names = db::get_module_names(&st.pool, &query.q, MAX_ITEMS as i64 + 1).await?;
+    Ok(HttpResponse::Ok().json(Items::new(names, None)))
+}
+
+// Non-yanked versions of a package, newest first, preselecting the latest
+// stable one
+pub async fn versions(
+    st: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<Completion>,
+) -> Result<HttpResponse, Error> {
+    let name = path.into_inner();
+    let package = match db::get_package(&st.pool, name.clone()).await {
+        Ok(package) => package,
+        // unknown names complete to nothing, other failures are errors
+        Err(ApiError::NotFound) => return Ok(HttpResponse::Ok().json(Items::new(vec![], None))),
+        Err(e) => return Err(e.into()),
+    };
+    let versions = db::get_package_versions(&st.pool, &name).await?;
+    let mut versions: Vec<_> = versions
+        .into_iter()
+        .filter(|v| !v.yanked && !v.malicious && v.version.starts_with(&query.q))
+        .map(|v| (parse_version(&v.version), v.version))
+        .collect();
+    // versions that don't parse as semver sort last
+    versions.sort_by(|a, b| match (&a.0, &b.0) {
+        (Some(a), Some(b)) => b.cmp_precedence(a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    let versions = versions.into_iter().map(|(_, v)| v).collect();
+    let preselect = package.latest_stable_version.or(package.latest_version);
+    Ok(HttpResponse::Ok().json(Items::new(versions, preselect)))
+}
+
+// File paths of a version starting with the query, as they are imported
+pub async fn paths(
+    st: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<Completion>,
+) -> Result<HttpResponse, Error> {
+    let (name, version) = path.into_inner();
+    let version = match db::get_package_version(&st.pool, name, version).await {
+        Ok(version) => version,
+        Err(ApiError::NotFound) => return Ok(HttpResponse::Ok().json(Items::new(vec![], None))),
+        Err(e) => return Err(e.into()),
+    };
+    let paths = version
+        .files
+        .iter()
+        .filter_map(|file| import_path(&version.prefix, &file.path))
+        .filter(|path| path.starts_with(&query.q))
+        .collect();
+    Ok(HttpResponse::Ok().json(Items::new(paths, None)))
+}
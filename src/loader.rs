@@ -0,0 +1,168 @@
+// Per-request batching of relationship lookups, so resolving the owners of a
+// list of 100 packages runs one query instead of 100.
+//
+// A resolver queues its key and yields once. Juniper polls the fields of every
+// item of a list together, so by the time a resolver runs again its siblings
+// have queued their keys as well, and the first of them to continue loads the
+// whole batch. Everyone else awaits that same batch.
+//
+// Batching thus assumes that a single yield is enough for every sibling to
+// reach its own `load`. A sibling that awaits something else first, such as
+// another query, misses the batch and is loaded in one of its own. The results
+// stay correct either way, only the number of queries grows.
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use crate::db::{self, Pool};
+use crate::error::ApiResult;
+use crate::schema::{
+    Collaborator, Organization, OrganizationMember, Package, PackageVersion, PublicUser,
+};
+
+type Batch<K, V> = Shared<BoxFuture<'static, ApiResult<Arc<HashMap<K, V>>>>>;
+type Fetch<K, V> = fn(Pool, Vec<K>) -> BoxFuture<'static, ApiResult<HashMap<K, V>>>;
+
+pub struct Loader<K, V> {
+    fetch: Fetch<K, V>,
+    state: Mutex<State<K, V>>,
+}
+
+struct State<K, V> {
+    // keys waiting for the next batch
+    queued: Vec<K>,
+    // the batch each key was or is being loaded in
+    batches: HashMap<K, Batch<K, V>>,
+}
+
+impl<K, V> Loader<K, V>
+where
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    pub fn new(fetch: Fetch<K, V>) -> Loader<K, V> {
+        Loader {
+            fetch,
+            state: Mutex::new(State {
+                queued: vec![],
+                batches: HashMap::new(),
+            }),
+        }
+    }
+
+    // load the value of `key`, which is None if there is none
+    pub async fn load(&self, pool: &Pool, key: K) -> ApiResult<Option<V>> {
+        if !self.state.lock().unwrap().batches.contains_key(&key) {
+            self.state.lock().unwrap().queued.push(key.clone());
+            // tokio 0.2 marks `yield_now` must_use, which the lint applies to
+            // its `()` output
+            #[allow(unused_must_use)]
+            tokio::task::yield_now().await;
+        }
+        let batch = {
+            let mut state = self.state.lock().unwrap();
+            if !state.batches.contains_key(&key) {
+                // the first of its batch to continue, so send the batch off
+                let keys: Vec<K> = state.queued.drain(..).collect();
+                let batch = (self.fetch)(pool.clone(), keys.clone())
+                    .map(|res| res.map(Arc::new))
+                    .boxed()
+                    .shared();
+                for key in keys {
+                    state.batches.insert(key, batch.clone());
+                }
+            }
+            state.batches[&key].clone()
+        };
+        Ok(batch.await?.get(&key).cloned())
+    }
+}
+
+// The loaders of a request
+pub struct Loaders {
+    // users by name
+    pub users: Loader<String, PublicUser>,
+    // packages by name
+    pub packages: Loader<String, Package>,
+    // versions by package name, newest first
+    pub versions: Loader<String, Vec<PackageVersion>>,
+    // collaborators by package name, by when they joined
+    pub collaborators: Loader<String, Vec<Collaborator>>,
+    // organizations by name
+    pub organizations: Loader<String, Organization>,
+    // members by organization name, by when they joined
+    pub members: Loader<String, Vec<OrganizationMember>>,
+}
+
+impl Loaders {
+    pub fn new() -> Loaders {
+        Loaders {
+            users: Loader::new(|pool, names| {
+                async move { db::get_users_by_names(&pool, names).await }.boxed()
+            }),
+            packages: Loader::new(|pool, names| {
+                async move { db::get_packages_by_names(&pool, names).await }.boxed()
+            }),
+            versions: Loader::new(|pool, names| {
+                async move { db::get_versions_by_packages(&pool, names).await }.boxed()
+            }),
+            collaborators: Loader::new(|pool, names| {
+                async move { db::get_collaborators_by_packages(&pool, names).await }.boxed()
+            }),
+            organizations: Loader::new(|pool, names| {
+                async move { db::get_organizations_by_names(&pool, names).await }.boxed()
+            }),
+            members: Loader::new(|pool, names| {
+                async move { db::get_members_by_organizations(&pool, names).await }.boxed()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ApiError;
+    use bb8_postgres::PostgresConnectionManager;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_postgres::NoTls;
+
+    // a pool that never connects, the loaders under test don't query
+    fn pool() -> Pool {
+        let manager = PostgresConnectionManager::new("host=localhost".parse().unwrap(), NoTls);
+        Pool::builder().build_unchecked(manager)
+    }
+
+    #[actix_rt::test]
+    async fn load_batches_siblings() {
+        static FETCHES: AtomicUsize = AtomicUsize::new(0);
+        let loader: Loader<u32, u32> = Loader::new(|_, keys| {
+            FETCHES.fetch_add(1, Ordering::SeqCst);
+            // only even keys have a value
+            let values: HashMap<u32, u32> =
+                keys.into_iter().filter(|k| k % 2 == 0).map(|k| (k, k * 10)).collect();
+            async move { Ok(values) }.boxed()
+        });
+        let pool = pool();
+        let values = join_all((0..5).map(|k| loader.load(&pool, k))).await;
+        assert_eq!(values, vec![Ok(Some(0)), Ok(None), Ok(Some(20)), Ok(None), Ok(Some(40))]);
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 1);
+
+        // loaded keys are not fetched again, new ones go in a new batch
+        assert_eq!(loader.load(&pool, 2).await, Ok(Some(20)));
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 1);
+        assert_eq!(loader.load(&pool, 6).await, Ok(Some(60)));
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn load_shares_errors() {
+        let loader: Loader<u32, u32> =
+            Loader::new(|_, _| async move { Err(ApiError::Internal) }.boxed());
+        let pool = pool();
+        let values = join_all((0..3).map(|k| loader.load(&pool, k))).await;
+        assert_eq!(values, vec![Err(ApiError::Internal); 3]);
+    }
+}
@@ -6,96 +6,238 @@ use std::sync::Arc;
 
 use actix_cors::Cors;
 use actix_multipart::Multipart;
-use actix_web::{middleware, web, App, Error, HttpResponse, HttpServer};
+use actix_web::http::{header, StatusCode};
+use actix_web::{middleware, web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use futures::{StreamExt, TryStreamExt};
 use juniper::http::graphiql::graphiql_source;
 use juniper::http::GraphQLRequest;
+use std::collections::HashMap;
 use std::io::Write;
 use std::path::Path;
-use tokio_postgres::Client;
 use uuid::Uuid;
 
+mod auth;
 mod context;
 mod db;
+mod error;
+mod intellisense;
+mod loader;
+mod migrations;
+mod password;
 mod schema;
+mod serve;
+mod storage;
 mod twig;
 mod utils;
 
-use crate::schema::{create_schema, Schema};
+use crate::auth::Auth;
+use crate::error::ApiError;
+use crate::schema::{create_schema, NewPackageUpload, Schema};
+use crate::utils::{parse_version_req, sanitize_path};
 
 async fn graphiql() -> HttpResponse {
-    let html = graphiql_source("http://127.0.0.1:8080/graphql");
+    let html = graphiql_source("http://127.0.0.1:8080/graphql", None);
     HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
         .body(html)
 }
 
+// Resolve the `Authorization: Bearer <key>` header of a request, if any. Keys
+// that don't resolve to a user are rejected rather than treated as anonymous.
+async fn authenticate(st: &AppState, req: &HttpRequest) -> Result<Option<Auth>, ApiError> {
+    let header = match req.headers().get(header::AUTHORIZATION) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let key = header
+        .to_str()
+        .ok()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .map(str::trim)
+        .ok_or_else(|| ApiError::Unauthorized("Malformed Authorization header".to_string()))?;
+    db::authenticate(&st.pool, key)
+        .await?
+        .map(Some)
+        .ok_or_else(ApiError::not_authorized)
+}
+
+// The status of a request whose credentials were rejected: 401 unless
+// checking them failed
+fn auth_status(e: &ApiError) -> StatusCode {
+    match e {
+        ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+        e => actix_web::ResponseError::status_code(e),
+    }
+}
+
 async fn graphql(
     st: web::Data<AppState>,
+    req: HttpRequest,
     data: web::Json<GraphQLRequest>,
 ) -> Result<HttpResponse, Error> {
-    let user = web::block(move || {
-        let res = data.execute(
-            &st.st,
-            &context::GraphQLContext {
-                pool: Arc::clone(&st.pool),
-            },
-        );
-        Ok::<_, serde_json::error::Error>(serde_json::to_string(&res)?)
-    })
-    .await?;
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .body(user))
+    let auth = match authenticate(&st, &req).await {
+        Ok(auth) => auth,
+        Err(e) => {
+            return Ok(HttpResponse::build(auth_status(&e)).json(serde_json::json!({
+                "data": null,
+                "errors": [{ "message": e.to_string(), "extensions": { "code": e.code() } }]
+            })))
+        }
+    };
+    let ctx = context::GraphQLContext {
+        pool: st.pool.clone(),
+        auth,
+        loaders: loader::Loaders::new(),
+    };
+    let res = data.execute(&st.st, &ctx).await;
+    Ok(HttpResponse::Ok().json(res))
 }
 
 #[derive(serde::Deserialize, Debug)]
 pub struct Config {
-    pub api_key: String,
     pub package_name: String,
+    pub version: String,
+    #[serde(default = "default_entry")]
+    pub entry: String,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default = "default_stable")]
+    pub stable: bool,
+}
+
+fn default_entry() -> String {
+    "/mod.ts".to_string()
+}
+
+// versions are stable unless published otherwise, as in "package-uploads"
+fn default_stable() -> bool {
+    true
+}
+
+async fn upload_package(
+    st: web::Data<AppState>,
+    req: HttpRequest,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    // authenticate the publisher before accepting any files
+    let auth = match authenticate(&st, &req).await {
+        Ok(Some(auth)) => auth,
+        Ok(None) => return Ok(HttpResponse::Unauthorized().json(ApiError::not_authorized().body())),
+        Err(e) => return Ok(HttpResponse::build(auth_status(&e)).json(e.body())),
+    };
+    // stage the uploaded files under a unique directory for twig to pick up
+    let tmp_id = Uuid::new_v4().to_simple().to_string();
+    let tmp_dir = format!("tmp/{}", tmp_id);
+    let res = publish_upload(&st, &auth, payload, &tmp_id, &tmp_dir).await;
+    let _ = web::block(move || std::fs::remove_dir_all(tmp_dir)).await;
+    res
 }
 
-async fn upload_package(mut payload: Multipart) -> Result<HttpResponse, Error> {
+async fn publish_upload(
+    st: &AppState,
+    auth: &Auth,
+    mut payload: Multipart,
+    tmp_id: &str,
+    tmp_dir: &str,
+) -> Result<HttpResponse, Error> {
     // iterate over multipart stream
     let mut fields = String::new();
+    // the manifest keys of the staged files
+    let mut paths = Vec::new();
     while let Ok(Some(mut field)) = payload.try_next().await {
-        let content_type = field.content_disposition().unwrap();
-        let mime_type = field.content_type();
-        println!("{}", mime_type.type_());
-        let filename = content_type.get_filename();
-        let unique_name = Uuid::new_v4().to_simple().to_string();
-        let filepath = format!("tmp/{}", filename.unwrap_or("none"));
-        // File::create is blocking operation, use threadpool
-        let mut f = web::block(move || std::fs::File::create(Path::new(&filepath)))
-            .await
-            .unwrap();
-        // Field in turn is stream of *Bytes* object
-        while let Some(chunk) = field.next().await {
-            let data = chunk.unwrap();
-            match filename {
-                None => {
-                    fields = std::str::from_utf8(&data)?.to_string();
+        let filename = field
+            .content_disposition()
+            .and_then(|cd| cd.get_filename().map(sanitize_path));
+        match filename {
+            None => {
+                // the only field without a filename is the JSON config
+                while let Some(chunk) = field.next().await {
+                    fields.push_str(std::str::from_utf8(&chunk?)?);
                 }
-                Some(_) => {
+            }
+            Some(filename) => {
+                let filepath = Path::new(tmp_dir).join(&filename);
+                paths.push(format!("/{}", filename));
+                // File::create is blocking operation, use threadpool
+                let mut f = web::block(move || {
+                    if let Some(parent) = filepath.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    std::fs::File::create(filepath)
+                })
+                .await?;
+                // Field in turn is stream of *Bytes* object
+                while let Some(chunk) = field.next().await {
+                    let data = chunk?;
                     // filesystem operations are blocking, we have to use threadpool
                     f = web::block(move || f.write_all(&data).map(|_| f)).await?;
                 }
             }
         }
     }
-    let cnf: Config = serde_json::from_str(&fields)?;
-    Ok(HttpResponse::Ok().into())
+    let cnf: Config = serde_json::from_str(&fields)
+        .map_err(|e| ApiError::Validation(format!("Invalid package config: {}", e)))?;
+
+    // make sure the publisher may publish the package
+    if !auth.can_publish(&cnf.package_name) {
+        return Err(ApiError::not_authorized().into());
+    }
+
+    let upload = NewPackageUpload {
+        name: cnf.package_name,
+        version: cnf.version,
+        entry: cnf.entry,
+        prefix: cnf.prefix,
+        stable: cnf.stable,
+    };
+    // reject malformed, overlong and taken versions, empty uploads and missing
+    // entries before anything is pushed to storage, where files stay for good
+    db::check_upload(&st.pool, auth, &upload, &paths).await?;
+
+    // push the staged files to storage and build the file manifest
+    let txs = match twig::tx(tmp_id.to_string()).await {
+        Ok(txs) => txs,
+        Err(e) => {
+            eprintln!("failed to push {} to storage: {}", tmp_id, e);
+            return Ok(HttpResponse::BadGateway().json(ApiError::Internal.body()));
+        }
+    };
+    let files: HashMap<String, db::Files> = txs
+        .into_iter()
+        .map(|tx| {
+            let path = format!("/{}", sanitize_path(&tx.relative_path));
+            (
+                path.clone(),
+                db::Files {
+                    in_manifest: path,
+                    tx_id: tx.tx_id,
+                },
+            )
+        })
+        .collect();
+
+    let version = db::create_package_uploads(&st.pool, auth, upload, files).await?;
+    Ok(HttpResponse::Created().json(version))
 }
 
-async fn index(
+// Resolve a semver range to the highest matching version of a package
+async fn resolve_version(
     st: web::Data<AppState>,
-    data: web::Json<GraphQLRequest>,
+    path: web::Path<(String, String)>,
 ) -> Result<HttpResponse, Error> {
+    let (name, range) = path.into_inner();
+    let req = parse_version_req(&range)
+        .ok_or_else(|| ApiError::Validation(format!("{} is not a valid version range", range)))?;
+    let version = db::resolve_version(&st.pool, name, &req).await?;
+    Ok(HttpResponse::Ok().json(version))
+}
+
+async fn index() -> Result<HttpResponse, Error> {
     Ok(HttpResponse::Ok().body("Welcome to Nest.land's Rust API"))
 }
 
 pub struct AppState {
-    pool: Arc<Client>,
+    pool: db::Pool,
     st: Arc<Schema>,
 }
 
@@ -103,23 +245,58 @@ pub struct AppState {
 async fn main() -> io::Result<()> {
     std::env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
-    let client = db::connect().await.unwrap();
+    let pool = db::connect().await.unwrap();
+    // Apply pending migrations with `--migrate` or DB_AUTO_MIGRATE=true
+    let migrate = std::env::args().any(|arg| arg == "--migrate")
+        || dotenv::var("DB_AUTO_MIGRATE").is_ok_and(|x| x == "true");
+    migrations::run(&pool, migrate)
+        .await
+        .map_err(io::Error::other)?;
+    // keys hashed without a secret can be brute-forced from a leaked table, so
+    // that takes an explicit opt-out
+    if dotenv::var("API_KEY_SECRET").unwrap_or_default().is_empty() {
+        if !dotenv::var("ALLOW_EMPTY_API_KEY_SECRET").is_ok_and(|x| x == "true") {
+            return Err(io::Error::other(
+                "API_KEY_SECRET is not set, set it or ALLOW_EMPTY_API_KEY_SECRET=true",
+            ));
+        }
+        eprintln!("warning: API_KEY_SECRET is not set, API keys are hashed without a secret");
+    }
+    password::check_config().map_err(io::Error::other)?;
+    db::hash_plaintext_keys(&pool).await.map_err(io::Error::other)?;
     // Create Juniper schema
     let schema = std::sync::Arc::new(create_schema());
-    let conn = std::sync::Arc::new(client);
     // Start http server
     HttpServer::new(move || {
         App::new()
             .wrap(Cors::new().supports_credentials().finish())
             .data(AppState {
                 st: schema.clone(),
-                pool: conn.clone(),
+                pool: pool.clone(),
             })
             .wrap(middleware::Logger::default())
             .service(web::resource("/").route(web::get().to(index)))
             .service(web::resource("/graphql").route(web::post().to(graphql)))
             .service(web::resource("/graphiql").route(web::get().to(graphiql)))
             .service(web::resource("/package").route(web::post().to(upload_package)))
+            .service(web::resource("/resolve/{name}/{range}").route(web::get().to(resolve_version)))
+            .service(
+                web::resource("/.well-known/deno-import-intellisense.json")
+                    .route(web::get().to(intellisense::config)),
+            )
+            .service(web::resource("/intellisense/modules").route(web::get().to(intellisense::modules)))
+            .service(
+                web::resource("/intellisense/modules/{module}/versions")
+                    .route(web::get().to(intellisense::versions)),
+            )
+            .service(
+                web::resource("/intellisense/modules/{module}/{version}/paths")
+                    .route(web::get().to(intellisense::paths)),
+            )
+            // module files, registered last as the pattern matches most paths.
+            // Packages can't be named after the routes above, see
+            // `utils::is_reserved_name`.
+            .service(web::resource("/{module}/{path:.+}").route(web::get().to(serve::module_file)))
     })
     .bind("127.0.0.1:8080")?
     .run()
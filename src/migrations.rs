@@ -0,0 +1,165 @@
+//! Embedded, versioned schema migrations
+//!
+//! Every migration lives in `migrations/V<version>__<name>.sql` and is baked
+//! into the binary. Applied versions are tracked in `schema_migrations`.
+use crate::db::Pool;
+use chrono::Utc;
+use std::collections::HashSet;
+
+struct Migration {
+    version: i32,
+    name: &'static str,
+    sql: &'static str,
+}
+
+// All known migrations, in the order they have to be applied
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/V1__initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "login_throttling",
+        sql: include_str!("../migrations/V2__login_throttling.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "tokens",
+        sql: include_str!("../migrations/V3__tokens.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "hashed_api_keys",
+        sql: include_str!("../migrations/V4__hashed_api_keys.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "admins",
+        sql: include_str!("../migrations/V5__admins.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "version_stability",
+        sql: include_str!("../migrations/V6__version_stability.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "yanked_versions",
+        sql: include_str!("../migrations/V7__yanked_versions.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "package_search",
+        sql: include_str!("../migrations/V8__package_search.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "package_owners",
+        sql: include_str!("../migrations/V9__package_owners.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "collaborators",
+        sql: include_str!("../migrations/V10__collaborators.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "organizations",
+        sql: include_str!("../migrations/V11__organizations.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "upload_name_length",
+        sql: include_str!("../migrations/V12__upload_name_length.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "required_timestamps",
+        sql: include_str!("../migrations/V13__required_timestamps.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "login_tokens",
+        sql: include_str!("../migrations/V14__login_tokens.sql"),
+    },
+];
+
+// Arbitrary key for the advisory lock that serializes concurrent migrators
+const MIGRATION_LOCK: i64 = 0x6e65_7374;
+
+// Check the database schema version and, if `apply` is set, run every
+// pending migration. Refuses to continue when the database has been migrated
+// by a newer build, or when migrations are pending and `apply` is not set.
+pub async fn run(db: &Pool, apply: bool) -> Result<(), String> {
+    let mut conn = db.get().await.map_err(|e| e.to_string())?;
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name VARCHAR(255) NOT NULL,
+            appliedAt timestamptz NOT NULL
+        )",
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    conn.execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let res = async {
+        let applied: HashSet<i32> = conn
+            .query("SELECT version FROM schema_migrations", &[])
+            .await
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        if let Some(unknown) = applied
+            .iter()
+            .filter(|v| !MIGRATIONS.iter().any(|m| m.version == **v))
+            .max()
+        {
+            return Err(format!(
+                "database schema version {} is unknown to this build",
+                unknown
+            ));
+        }
+
+        let pending: Vec<&Migration> = MIGRATIONS
+            .iter()
+            .filter(|m| !applied.contains(&m.version))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if !apply {
+            return Err(format!(
+                "database schema is missing {} migration(s), restart with --migrate to apply them",
+                pending.len()
+            ));
+        }
+
+        for migration in pending {
+            let tx = conn.transaction().await.map_err(|e| e.to_string())?;
+            tx.batch_execute(migration.sql)
+                .await
+                .map_err(|e| format!("migration V{} failed: {}", migration.version, e))?;
+            tx.execute(
+                "INSERT INTO schema_migrations (version, name, appliedAt) VALUES ($1, $2, $3)",
+                &[&migration.version, &migration.name, &Utc::now()],
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+            tx.commit().await.map_err(|e| e.to_string())?;
+            println!("Applied migration V{}__{}", migration.version, migration.name);
+        }
+        Ok(())
+    }
+    .await;
+
+    conn.execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])
+        .await
+        .map_err(|e| e.to_string())?;
+    res
+}
@@ -0,0 +1,110 @@
+//! Password hashing
+//!
+//! Passwords are hashed with Argon2id and stored as PHC strings
+//! (`$argon2id$v=19$m=..,t=..,p=..$salt$hash`), which carry their own salt and
+//! cost parameters so existing hashes keep verifying when the costs change.
+use crate::utils::env_or;
+use argon2::{Config, ThreadMode, Variant, Version};
+use constant_time_eq::constant_time_eq;
+use rand::RngCore;
+use tokio::task::spawn_blocking;
+
+// current cost parameters, tunable through ARGON2_MEM_COST (KiB),
+// ARGON2_TIME_COST and ARGON2_LANES
+fn config<'a>() -> Config<'a> {
+    let lanes = env_or("ARGON2_LANES", 1);
+    Config {
+        variant: Variant::Argon2id,
+        version: Version::Version13,
+        mem_cost: env_or("ARGON2_MEM_COST", 19456),
+        time_cost: env_or("ARGON2_TIME_COST", 2),
+        lanes,
+        thread_mode: ThreadMode::from_threads(lanes),
+        secret: &[],
+        ad: &[],
+        hash_length: 32,
+    }
+}
+
+// fail unless the ARGON2_* settings can hash passwords, which the server
+// checks on startup rather than on every login
+pub fn check_config() -> Result<(), String> {
+    argon2::hash_encoded(b"", &[0u8; 16], &config())
+        .map(|_| ())
+        .map_err(|e| format!("invalid ARGON2_* settings: {}", e))
+}
+
+// hash a password with a fresh random salt
+pub async fn hash(password: String) -> String {
+    spawn_blocking(move || {
+        let mut salt = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut salt);
+        argon2::hash_encoded(password.as_bytes(), &salt, &config())
+            .expect("ARGON2_* settings are checked on startup")
+    })
+    .await
+    .unwrap()
+}
+
+// check a password against its stored form, which is either a PHC string or,
+// for rows created before hashing was introduced, the plaintext itself
+pub async fn verify(stored: String, password: String) -> bool {
+    if !is_hashed(&stored) {
+        return constant_time_eq(stored.as_bytes(), password.as_bytes());
+    }
+    spawn_blocking(move || argon2::verify_encoded(&stored, password.as_bytes()).unwrap_or(false))
+        .await
+        .unwrap()
+}
+
+// whether a stored password should be replaced by a fresh hash, because it is
+// still in plaintext or was hashed with other parameters than the current ones
+pub fn needs_rehash(stored: &str) -> bool {
+    if !is_hashed(stored) {
+        return true;
+    }
+    let config = config();
+    let expected = format!(
+        "$argon2id$v=19$m={},t={},p={}$",
+        config.mem_cost, config.time_cost, config.lanes
+    );
+    !stored.starts_with(&expected)
+}
+
+fn is_hashed(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(check_config(), Ok(()));
+    }
+
+    #[actix_rt::test]
+    async fn verify_hashed_and_plaintext() {
+        let stored = hash("hunter2".to_string()).await;
+        assert!(is_hashed(&stored));
+        assert!(verify(stored.clone(), "hunter2".to_string()).await);
+        assert!(!verify(stored, "hunter3".to_string()).await);
+        assert!(verify("hunter2".to_string(), "hunter2".to_string()).await);
+        assert!(!verify("hunter2".to_string(), "hunter".to_string()).await);
+    }
+
+    #[actix_rt::test]
+    async fn needs_rehash_current_hash() {
+        assert!(!needs_rehash(&hash("hunter2".to_string()).await));
+    }
+
+    #[test]
+    fn needs_rehash_outdated() {
+        // plaintext from before hashing was introduced
+        assert!(needs_rehash("hunter2"));
+        // other costs, or another variant, than the current ones
+        assert!(needs_rehash("$argon2id$v=19$m=4096,t=3,p=1$c2FsdHNhbHQ$aGFzaA"));
+        assert!(needs_rehash("$argon2i$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaA"));
+    }
+}
@@ -1,77 +1,567 @@
 //! Juniper GraphQL handling done here
+use crate::auth::Auth;
 use crate::context::GraphQLContext;
 use crate::db::{
-    create_user, get_modules, get_package, get_users, get_user_by_key, publish_package, get_user_by_name,
+    accept_invite, add_organization_member, change_collaborator_role,
+    change_organization_member_role, create_organization, create_token, create_user, get_invites,
+    get_modules, get_organization, get_package, get_package_version, get_tokens, get_user,
+    get_users, invite_collaborator, login_user, publish_package, remove_collaborator,
+    remove_organization_member, resolve_version, revoke_token, get_user_by_name, search_packages,
+    yank_version,
 };
-use juniper::FieldResult;
-use juniper::RootNode;
-use juniper::{GraphQLInputObject, GraphQLObject};
-use std::sync::Arc;
-use tokio::runtime::Runtime;
+use crate::error::{ApiError, ApiResult};
+use crate::utils::parse_version_req;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use juniper::{EmptySubscription, RootNode};
+use juniper::{GraphQLEnum, GraphQLInputObject, GraphQLObject, GraphQLUnion};
+use serde::Serialize;
 
 // Define GraphQL schema for package retrival
-#[derive(GraphQLObject)]
-#[graphql(description = "A nest.land package")]
+#[derive(Clone)]
 pub struct Package {
     pub name: String,
     pub normalized_name: String,
     pub owner: String,
     pub description: String,
     pub repository: String,
-    pub latest_version: String,
-    pub latest_stable_version: String,
+    pub latest_version: Option<String>,
+    pub latest_stable_version: Option<String>,
     pub package_upload_names: Vec<String>,
+    pub keywords: Vec<String>,
+    pub downloads: i32,
     pub locked: bool,
     pub malicious: bool,
     pub unlisted: bool,
-    pub updated_at: String,
-    pub created_at: String,
+    pub updated_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[juniper::graphql_object(Context = GraphQLContext, description = "A nest.land package")]
+impl Package {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn normalized_name(&self) -> &str {
+        &self.normalized_name
+    }
+    #[graphql(description = "The user or organization the package belongs to")]
+    async fn owner(&self, ctx: &GraphQLContext) -> ApiResult<Owner> {
+        // users and organizations share their names, so at most one matches
+        if let Some(user) = ctx.loaders.users.load(&ctx.pool, self.owner.clone()).await? {
+            return Ok(Owner::User(user));
+        }
+        let organization = ctx.loaders.organizations.load(&ctx.pool, self.owner.clone()).await?;
+        organization.map(Owner::Organization).ok_or(ApiError::NotFound)
+    }
+    #[graphql(description = "The name of the user or organization the package belongs to")]
+    fn owner_name(&self) -> &str {
+        &self.owner
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn repository(&self) -> &str {
+        &self.repository
+    }
+    #[graphql(description = "The highest published version, prereleases included")]
+    fn latest_version(&self) -> Option<&str> {
+        self.latest_version.as_deref()
+    }
+    #[graphql(description = "The highest version published as stable that is not a prerelease")]
+    fn latest_stable_version(&self) -> Option<&str> {
+        self.latest_stable_version.as_deref()
+    }
+    fn package_upload_names(&self) -> &Vec<String> {
+        &self.package_upload_names
+    }
+    fn keywords(&self) -> &Vec<String> {
+        &self.keywords
+    }
+    #[graphql(description = "How often the entry module of any version was served")]
+    fn downloads(&self) -> i32 {
+        self.downloads
+    }
+    fn locked(&self) -> bool {
+        self.locked
+    }
+    fn malicious(&self) -> bool {
+        self.malicious
+    }
+    fn unlisted(&self) -> bool {
+        self.unlisted
+    }
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+    #[graphql(description = "The owner if it is a user, see `owner` for organizations")]
+    async fn owner_user(&self, ctx: &GraphQLContext) -> ApiResult<Option<PublicUser>> {
+        ctx.loaders.users.load(&ctx.pool, self.owner.clone()).await
+    }
+    #[graphql(description = "Published versions, newest first")]
+    async fn versions(&self, ctx: &GraphQLContext) -> ApiResult<Vec<PackageVersion>> {
+        let versions = ctx.loaders.versions.load(&ctx.pool, self.name.clone()).await?;
+        Ok(versions.unwrap_or_default())
+    }
+    #[graphql(description = "Everyone who may publish the package, by when they joined")]
+    async fn collaborators(&self, ctx: &GraphQLContext) -> ApiResult<Vec<Collaborator>> {
+        let collaborators = ctx.loaders.collaborators.load(&ctx.pool, self.name.clone()).await?;
+        Ok(collaborators.unwrap_or_default())
+    }
+}
+
+// Define graphql schema for package collaborators
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[graphql(description = "What a collaborator may do with a package, or a member with every package of an organization. Each role includes the ones before it.")]
+pub enum PackageRole {
+    #[graphql(description = "Publish new versions")]
+    Publisher,
+    #[graphql(description = "Update the package details, yank versions and create packages for an organization")]
+    Maintainer,
+    #[graphql(description = "Manage collaborators, or the members and tokens of an organization")]
+    Owner,
+}
+
+impl PackageRole {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PackageRole::Publisher => "publisher",
+            PackageRole::Maintainer => "maintainer",
+            PackageRole::Owner => "owner",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<PackageRole> {
+        match role {
+            "publisher" => Some(PackageRole::Publisher),
+            "maintainer" => Some(PackageRole::Maintainer),
+            "owner" => Some(PackageRole::Owner),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Collaborator {
+    pub package: String,
+    pub user_name: String,
+    pub role: PackageRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[juniper::graphql_object(Context = GraphQLContext, description = "A user who may publish a package")]
+impl Collaborator {
+    fn package(&self) -> &str {
+        &self.package
+    }
+    fn user_name(&self) -> &str {
+        &self.user_name
+    }
+    async fn user(&self, ctx: &GraphQLContext) -> ApiResult<PublicUser> {
+        let user = ctx.loaders.users.load(&ctx.pool, self.user_name.clone()).await?;
+        user.ok_or(ApiError::NotFound)
+    }
+    fn role(&self) -> PackageRole {
+        self.role
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
 }
 
-// Define GraphQL schema for User retrival
 #[derive(GraphQLObject)]
-#[graphql(description = "A nest.land package author")]
+#[graphql(description = "An invitation to collaborate on a package, pending until accepted")]
+pub struct Invite {
+    pub package: String,
+    pub user_name: String,
+    pub role: PackageRole,
+    pub invited_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// Define GraphQL schema for package version retrival
+#[derive(Clone, Serialize)]
+pub struct PackageVersion {
+    pub name: String,
+    pub package: String,
+    pub version: String,
+    pub entry: String,
+    pub prefix: String,
+    pub malicious: bool,
+    pub stable: bool,
+    pub yanked: bool,
+    pub files: Vec<PackageFile>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[juniper::graphql_object(
+    Context = GraphQLContext,
+    description = "A published version of a nest.land package"
+)]
+impl PackageVersion {
+    #[graphql(description = "The release name, e.g. `eggs@v0.1.0`")]
+    fn name(&self) -> &str {
+        &self.name
+    }
+    async fn package(&self, ctx: &GraphQLContext) -> ApiResult<Package> {
+        let package = ctx.loaders.packages.load(&ctx.pool, self.package.clone()).await?;
+        package.ok_or(ApiError::NotFound)
+    }
+    fn version(&self) -> &str {
+        &self.version
+    }
+    fn entry(&self) -> &str {
+        &self.entry
+    }
+    fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    fn malicious(&self) -> bool {
+        self.malicious
+    }
+    fn stable(&self) -> bool {
+        self.stable
+    }
+    #[graphql(description = "Yanked versions are skipped when resolving ranges")]
+    fn yanked(&self) -> bool {
+        self.yanked
+    }
+    fn files(&self) -> &Vec<PackageFile> {
+        &self.files
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+#[derive(GraphQLObject, Clone, Serialize)]
+#[graphql(description = "A file of a published package version")]
+pub struct PackageFile {
+    #[graphql(description = "Path of the file inside the package, e.g. `/mod.ts`")]
+    pub path: String,
+    pub in_manifest: String,
+    #[graphql(description = "The storage transaction holding the file contents")]
+    pub tx_id: String,
+}
+
+// Define GraphQL schema for User retrival
+#[derive(Clone)]
 pub struct User {
     pub name: String,
     pub normalized_name: String,
-    pub api_key: String,
+    pub api_key: Option<String>,
+    pub api_key_prefix: String,
     pub package_names: Vec<String>,
-    pub created_at: String,
+    pub admin: bool,
+    pub created_at: DateTime<Utc>,
 }
-#[derive(GraphQLObject)]
-#[graphql(description = "A nest.land package author [restricted]")]
+
+impl User {
+    // Private fields resolve for the user themselves and for site admins. A
+    // freshly issued key is only ever handed to the caller that proved to own
+    // the account, so that response may show them too.
+    fn authorize_private(&self, ctx: &GraphQLContext) -> ApiResult<()> {
+        let allowed = self.api_key.is_some()
+            || ctx
+                .auth
+                .as_ref()
+                .is_some_and(|auth| auth.can_view_private(&self.name));
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::not_authorized())
+        }
+    }
+}
+
+#[juniper::graphql_object(
+    Context = GraphQLContext,
+    description = "A nest.land package author, including private account data"
+)]
+impl User {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn normalized_name(&self) -> &str {
+        &self.normalized_name
+    }
+    fn package_names(&self) -> &Vec<String> {
+        &self.package_names
+    }
+    async fn packages(&self, ctx: &GraphQLContext) -> ApiResult<Vec<Package>> {
+        load_packages(ctx, &self.package_names).await
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+    #[graphql(description = "Only returned right after the key has been issued")]
+    fn api_key(&self, ctx: &GraphQLContext) -> ApiResult<Option<&str>> {
+        self.authorize_private(ctx)?;
+        Ok(self.api_key.as_deref())
+    }
+    fn api_key_prefix(&self, ctx: &GraphQLContext) -> ApiResult<&str> {
+        self.authorize_private(ctx)?;
+        Ok(self.api_key_prefix.as_str())
+    }
+    fn admin(&self, ctx: &GraphQLContext) -> ApiResult<bool> {
+        self.authorize_private(ctx)?;
+        Ok(self.admin)
+    }
+}
+
+#[derive(Clone)]
 pub struct PublicUser {
     pub name: String,
     pub normalized_name: String,
     pub package_names: Vec<String>,
-    pub created_at: String,
+    pub created_at: DateTime<Utc>,
 }
 
-// Define graphql schema for NewPackage
+#[juniper::graphql_object(
+    Context = GraphQLContext,
+    description = "A nest.land package author [restricted]"
+)]
+impl PublicUser {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn normalized_name(&self) -> &str {
+        &self.normalized_name
+    }
+    fn package_names(&self) -> &Vec<String> {
+        &self.package_names
+    }
+    async fn packages(&self, ctx: &GraphQLContext) -> ApiResult<Vec<Package>> {
+        load_packages(ctx, &self.package_names).await
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+// Define graphql schema for organizations
+#[derive(Clone)]
+pub struct Organization {
+    pub name: String,
+    pub normalized_name: String,
+    pub description: String,
+    pub package_names: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[juniper::graphql_object(
+    Context = GraphQLContext,
+    description = "A team account that owns packages on behalf of its members"
+)]
+impl Organization {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn normalized_name(&self) -> &str {
+        &self.normalized_name
+    }
+    fn description(&self) -> &str {
+        &self.description
+    }
+    fn package_names(&self) -> &Vec<String> {
+        &self.package_names
+    }
+    async fn packages(&self, ctx: &GraphQLContext) -> ApiResult<Vec<Package>> {
+        load_packages(ctx, &self.package_names).await
+    }
+    #[graphql(description = "Members by when they joined")]
+    async fn members(&self, ctx: &GraphQLContext) -> ApiResult<Vec<OrganizationMember>> {
+        let members = ctx.loaders.members.load(&ctx.pool, self.name.clone()).await?;
+        Ok(members.unwrap_or_default())
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+#[derive(Clone)]
+pub struct OrganizationMember {
+    pub organization: String,
+    pub user_name: String,
+    pub role: PackageRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[juniper::graphql_object(
+    Context = GraphQLContext,
+    description = "A user holding a role on every package of an organization"
+)]
+impl OrganizationMember {
+    fn organization(&self) -> &str {
+        &self.organization
+    }
+    fn user_name(&self) -> &str {
+        &self.user_name
+    }
+    async fn user(&self, ctx: &GraphQLContext) -> ApiResult<PublicUser> {
+        let user = ctx.loaders.users.load(&ctx.pool, self.user_name.clone()).await?;
+        user.ok_or(ApiError::NotFound)
+    }
+    fn role(&self) -> PackageRole {
+        self.role
+    }
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+}
+
+#[derive(GraphQLUnion)]
+#[graphql(context = GraphQLContext, description = "An account that can own packages")]
+pub enum Owner {
+    User(PublicUser),
+    Organization(Organization),
+}
+
+// load the packages named `names` through the request's loader, skipping any
+// that don't exist
+async fn load_packages(ctx: &GraphQLContext, names: &[String]) -> ApiResult<Vec<Package>> {
+    let packages = join_all(
+        names
+            .iter()
+            .map(|name| ctx.loaders.packages.load(&ctx.pool, name.clone())),
+    )
+    .await;
+    let mut loaded = vec![];
+    for package in packages {
+        loaded.extend(package?);
+    }
+    Ok(loaded)
+}
+
+// Define graphql schema for paginated lists
+#[derive(GraphQLObject)]
+#[graphql(description = "Information about a page of a connection")]
+pub struct PageInfo {
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub start_cursor: Option<String>,
+    pub end_cursor: Option<String>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A package in a list")]
+pub struct PackageEdge {
+    pub cursor: String,
+    pub node: Package,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A page of packages")]
+pub struct PackageConnection {
+    pub edges: Vec<PackageEdge>,
+    pub page_info: PageInfo,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A user in a list")]
+pub struct UserEdge {
+    pub cursor: String,
+    pub node: PublicUser,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A page of users")]
+pub struct UserConnection {
+    pub edges: Vec<UserEdge>,
+    pub page_info: PageInfo,
+}
+
+#[derive(GraphQLEnum, Clone, Copy, PartialEq)]
+pub enum OrderDirection {
+    Asc,
+    Desc,
+}
+
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum ModuleOrderField {
+    Name,
+    CreatedAt,
+    UpdatedAt,
+    Downloads,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "How to order packages, ties are broken by name")]
+pub struct ModuleOrder {
+    pub field: ModuleOrderField,
+    #[graphql(description = "Ascending when omitted")]
+    pub direction: Option<OrderDirection>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "Conditions a package has to meet, all of them when several are given")]
+pub struct ModuleFilter {
+    pub owner: Option<String>,
+    pub locked: Option<bool>,
+    pub unlisted: Option<bool>,
+    #[graphql(description = "Only packages last updated at or after this time")]
+    pub updated_since: Option<DateTime<Utc>>,
+}
+
+#[derive(GraphQLEnum, Clone, Copy)]
+pub enum UserOrderField {
+    Name,
+    CreatedAt,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "How to order users, ties are broken by name")]
+pub struct UserOrder {
+    pub field: UserOrderField,
+    #[graphql(description = "Ascending when omitted")]
+    pub direction: Option<OrderDirection>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A package matching a search")]
+pub struct SearchEdge {
+    pub cursor: String,
+    pub node: Package,
+    #[graphql(description = "Relevance weighted by popularity, higher is better")]
+    pub rank: f64,
+    #[graphql(description = "Part of the HTML-escaped description with matches wrapped in <mark>")]
+    pub snippet: String,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(context = GraphQLContext, description = "A page of search results")]
+pub struct SearchConnection {
+    pub edges: Vec<SearchEdge>,
+    pub page_info: PageInfo,
+}
+
+// Define graphql schema for NewPackageUpload
 #[derive(GraphQLInputObject)]
 #[graphql(description = "A nest.land package upload")]
 pub struct NewPackageUpload {
     pub name: String,
-    pub api_key: String,
-    pub description: String,
-    pub repository: String,
-    pub upload: bool,
+    pub version: String,
     pub entry: String,
+    pub prefix: String,
     pub stable: bool,
-    pub unlisted: bool,
-    pub version: String,
 }
 
 #[derive(GraphQLInputObject)]
 #[graphql(description = "A nest.land package")]
 pub struct NewPackage {
     pub name: String,
-    pub api_key: String,
     pub description: String,
     pub repository: String,
+    #[graphql(description = "Search keywords, left unchanged on updates when omitted")]
+    pub keywords: Option<Vec<String>>,
     pub locked: bool,
     pub malicious: bool,
     pub unlisted: bool,
+    #[graphql(description = "Create the package for this organization rather than yourself, ignored on updates")]
+    pub organization: Option<String>,
 }
 
 // Define graphql schema for NewPackage
@@ -82,68 +572,284 @@ pub struct NewUser {
     pub password: String,
 }
 
+// Define graphql schema for API tokens
+#[derive(GraphQLEnum, Clone, Copy, Debug, PartialEq)]
+#[graphql(description = "What an API token is allowed to do")]
+pub enum TokenScope {
+    #[graphql(description = "Publish packages and new versions")]
+    Publish,
+    #[graphql(description = "Read private account data")]
+    ReadPrivate,
+    #[graphql(description = "Everything, including managing tokens")]
+    Admin,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::Publish => "publish",
+            TokenScope::ReadPrivate => "read-private",
+            TokenScope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<TokenScope> {
+        match scope {
+            "publish" => Some(TokenScope::Publish),
+            "read-private" => Some(TokenScope::ReadPrivate),
+            "admin" => Some(TokenScope::Admin),
+            _ => None,
+        }
+    }
+}
+
 #[derive(GraphQLObject)]
-#[graphql(description = "Package upload result")]
-pub struct NewPackageResult {
-    pub ok: bool,
-    pub msg: String,
+#[graphql(description = "A named API token")]
+pub struct Token {
+    pub id: String,
+    pub name: String,
+    #[graphql(description = "The first characters of the token, to tell it apart")]
+    pub prefix: String,
+    pub scopes: Vec<TokenScope>,
+    #[graphql(description = "The only package this token may publish")]
+    pub package: Option<String>,
+    #[graphql(description = "The organization whose packages this token publishes")]
+    pub organization: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "A newly created API token, including its secret")]
+pub struct NewTokenResult {
+    pub token: String,
+    pub details: Token,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A new API token")]
+pub struct NewToken {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub package: Option<String>,
+    #[graphql(description = "Publish the packages of this organization you own, rather than your own. Only the PUBLISH scope is allowed.")]
+    pub organization: Option<String>,
+    #[graphql(description = "When the token stops working")]
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
 pub struct QueryRoot;
 
 // Define QueryRoot for GraphQL
-#[juniper::object(Context = GraphQLContext)]
+#[juniper::graphql_object(Context = GraphQLContext)]
 impl QueryRoot {
-    fn modules(ctx: &GraphQLContext) -> FieldResult<Vec<Package>> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(get_modules(Arc::clone(&ctx.pool)))?)
+    #[graphql(description = "Packages, `first` (20 by default, at most 100) per page after the `after` cursor")]
+    async fn modules(
+        ctx: &GraphQLContext,
+        first: Option<i32>,
+        after: Option<String>,
+        filter: Option<ModuleFilter>,
+        order_by: Option<ModuleOrder>,
+    ) -> ApiResult<PackageConnection> {
+        get_modules(&ctx.pool, first, after, filter, order_by).await
+    }
+    async fn package(ctx: &GraphQLContext, name: String) -> ApiResult<Package> {
+        get_package(&ctx.pool, name).await
+    }
+    async fn version(
+        ctx: &GraphQLContext,
+        name: String,
+        version: String,
+    ) -> ApiResult<PackageVersion> {
+        get_package_version(&ctx.pool, name, version).await
+    }
+    #[graphql(description = "Full-text search over listed packages, most relevant first")]
+    async fn search(
+        ctx: &GraphQLContext,
+        query: String,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> ApiResult<SearchConnection> {
+        search_packages(&ctx.pool, query, first, after).await
+    }
+    #[graphql(description = "The highest non-yanked version matching a semver range, e.g. `^1.2`")]
+    async fn resolve_version(
+        ctx: &GraphQLContext,
+        package: String,
+        range: String,
+    ) -> ApiResult<PackageVersion> {
+        let range = parse_version_req(&range)
+            .ok_or_else(|| ApiError::Validation(format!("{} is not a valid version range", range)))?;
+        resolve_version(&ctx.pool, package, &range).await
+    }
+    #[graphql(description = "Users, `first` (20 by default, at most 100) per page after the `after` cursor")]
+    async fn users(
+        ctx: &GraphQLContext,
+        first: Option<i32>,
+        after: Option<String>,
+        order_by: Option<UserOrder>,
+    ) -> ApiResult<UserConnection> {
+        get_users(&ctx.pool, first, after, order_by).await
+    }
+    async fn organization(ctx: &GraphQLContext, name: String) -> ApiResult<Organization> {
+        get_organization(&ctx.pool, name).await
     }
-    fn package(ctx: &GraphQLContext, name: String) -> FieldResult<Package> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(get_package(Arc::clone(&ctx.pool), name))?)
+    async fn user_by_name(ctx: &GraphQLContext, name: String) -> ApiResult<PublicUser> {
+        get_user_by_name(&ctx.pool, name).await
     }
-    fn users(ctx: &GraphQLContext) -> FieldResult<Vec<PublicUser>> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(get_users(Arc::clone(&ctx.pool)))?)
+    #[graphql(description = "The authenticated user, including private account data")]
+    fn viewer(ctx: &GraphQLContext) -> ApiResult<User> {
+        Ok(authorize(ctx, TokenScope::ReadPrivate)?.user.clone())
     }
-    fn user_by_name(ctx: &GraphQLContext, name: String) -> FieldResult<PublicUser> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(get_user_by_name(Arc::clone(&ctx.pool), name))?)
+    #[graphql(description = "Private account data of any user, for site admins")]
+    async fn user(ctx: &GraphQLContext, name: String) -> ApiResult<User> {
+        let auth = authorize(ctx, TokenScope::ReadPrivate)?;
+        if !auth.can_view_private(&name) {
+            return Err(ApiError::not_authorized());
+        }
+        get_user(&ctx.pool, name).await
     }
-    fn user(ctx: &GraphQLContext, api_key: String) -> FieldResult<User> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(get_user_by_key(Arc::clone(&ctx.pool), api_key))?)
+    async fn tokens(ctx: &GraphQLContext) -> ApiResult<Vec<Token>> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        get_tokens(&ctx.pool, &auth.user).await
+    }
+    #[graphql(description = "Invitations to collaborate the authenticated user has not accepted yet")]
+    async fn invites(ctx: &GraphQLContext) -> ApiResult<Vec<Invite>> {
+        let auth = authorize(ctx, TokenScope::ReadPrivate)?;
+        get_invites(&ctx.pool, &auth.user).await
     }
 }
 
 pub struct MutationRoot;
 
 // Define MutationRoot for GraphQL
-#[juniper::object(Context = GraphQLContext)]
+#[juniper::graphql_object(Context = GraphQLContext)]
 impl MutationRoot {
-    fn create_user(ctx: &GraphQLContext, new_user: NewUser) -> FieldResult<User> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(create_user(Arc::clone(&ctx.pool), new_user))?)
+    async fn create_user(ctx: &GraphQLContext, new_user: NewUser) -> ApiResult<User> {
+        create_user(&ctx.pool, new_user).await
+    }
+    async fn login(ctx: &GraphQLContext, name: String, password: String) -> ApiResult<User> {
+        login_user(&ctx.pool, name, password).await
     }
-    fn create_package(
+    #[graphql(description = "Create a package, or update the details of one you own or maintain")]
+    async fn create_package(
         ctx: &GraphQLContext,
         new_package: NewPackage,
-    ) -> FieldResult<NewPackageResult> {
-        Ok(Runtime::new()
-            .unwrap()
-            .block_on(publish_package(Arc::clone(&ctx.pool), new_package))?)
+    ) -> ApiResult<Package> {
+        let auth = authorize_package(ctx, &new_package.name)?;
+        publish_package(&ctx.pool, &auth.user, new_package).await
+    }
+    #[graphql(description = "Yank a version so ranges no longer resolve to it, or restore it")]
+    async fn yank_version(
+        ctx: &GraphQLContext,
+        name: String,
+        version: String,
+        yanked: bool,
+    ) -> ApiResult<PackageVersion> {
+        let auth = authorize_package(ctx, &name)?;
+        yank_version(&ctx.pool, &auth.user, name, version, yanked).await
+    }
+    #[graphql(description = "Invite a user to collaborate on a package you own, or change the role of a pending invitation")]
+    async fn invite_collaborator(
+        ctx: &GraphQLContext,
+        package: String,
+        user: String,
+        role: PackageRole,
+    ) -> ApiResult<Invite> {
+        let auth = authorize_package(ctx, &package)?;
+        invite_collaborator(&ctx.pool, &auth.user, package, user, role).await
+    }
+    #[graphql(description = "Accept an invitation to collaborate on a package")]
+    async fn accept_invite(ctx: &GraphQLContext, package: String) -> ApiResult<Collaborator> {
+        let auth = authorize_package(ctx, &package)?;
+        accept_invite(&ctx.pool, &auth.user, package).await
+    }
+    #[graphql(description = "Change the role of a collaborator on a package you own")]
+    async fn change_collaborator_role(
+        ctx: &GraphQLContext,
+        package: String,
+        user: String,
+        role: PackageRole,
+    ) -> ApiResult<Collaborator> {
+        let auth = authorize_package(ctx, &package)?;
+        change_collaborator_role(&ctx.pool, &auth.user, package, user, role).await
+    }
+    #[graphql(description = "Remove a collaborator or pending invitation from a package you own, or leave a package yourself")]
+    async fn remove_collaborator(ctx: &GraphQLContext, package: String, user: String) -> ApiResult<bool> {
+        let auth = authorize_package(ctx, &package)?;
+        remove_collaborator(&ctx.pool, &auth.user, package, user).await
+    }
+    #[graphql(description = "Create an organization, which you become the owner of")]
+    async fn create_organization(
+        ctx: &GraphQLContext,
+        name: String,
+        description: Option<String>,
+    ) -> ApiResult<Organization> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        create_organization(&ctx.pool, &auth.user, name, description.unwrap_or_default()).await
+    }
+    #[graphql(description = "Add a user to an organization you own")]
+    async fn add_organization_member(
+        ctx: &GraphQLContext,
+        organization: String,
+        user: String,
+        role: PackageRole,
+    ) -> ApiResult<OrganizationMember> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        add_organization_member(&ctx.pool, &auth.user, organization, user, role).await
+    }
+    #[graphql(description = "Change the role of a member of an organization you own")]
+    async fn change_organization_member_role(
+        ctx: &GraphQLContext,
+        organization: String,
+        user: String,
+        role: PackageRole,
+    ) -> ApiResult<OrganizationMember> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        change_organization_member_role(&ctx.pool, &auth.user, organization, user, role).await
+    }
+    #[graphql(description = "Remove a member from an organization you own, or leave an organization yourself")]
+    async fn remove_organization_member(
+        ctx: &GraphQLContext,
+        organization: String,
+        user: String,
+    ) -> ApiResult<bool> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        remove_organization_member(&ctx.pool, &auth.user, organization, user).await
+    }
+    async fn create_token(ctx: &GraphQLContext, new_token: NewToken) -> ApiResult<NewTokenResult> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        create_token(&ctx.pool, auth, new_token).await
+    }
+    async fn revoke_token(ctx: &GraphQLContext, id: String) -> ApiResult<Token> {
+        let auth = authorize(ctx, TokenScope::Admin)?;
+        revoke_token(&ctx.pool, auth, id).await
+    }
+}
+
+// make sure the request is authenticated with a credential that may publish
+// `package`. What the user may do with it depends on their role. Organization
+// tokens only publish versions, through `POST /package`.
+fn authorize_package<'a>(ctx: &'a GraphQLContext, package: &str) -> ApiResult<&'a Auth> {
+    let auth = authorize(ctx, TokenScope::Publish)?;
+    if !auth.can_publish(package) || auth.organization.is_some() {
+        return Err(ApiError::not_authorized());
+    }
+    Ok(auth)
+}
+
+// make sure the request is authenticated with a credential carrying `scope`
+fn authorize(ctx: &GraphQLContext, scope: TokenScope) -> ApiResult<&Auth> {
+    match &ctx.auth {
+        Some(auth) if auth.has_scope(scope) => Ok(auth),
+        _ => Err(ApiError::not_authorized()),
     }
 }
 
-pub type Schema = RootNode<'static, QueryRoot, MutationRoot>;
+pub type Schema = RootNode<'static, QueryRoot, MutationRoot, EmptySubscription<GraphQLContext>>;
 
 // Expose create schema method
 pub fn create_schema() -> Schema {
-    Schema::new(QueryRoot {}, MutationRoot {})
+    Schema::new(QueryRoot {}, MutationRoot {}, EmptySubscription::new())
 }
@@ -0,0 +1,187 @@
+// Serves the files of published versions so Deno can import them directly,
+// e.g. `import { x } from "https://<host>/eggs@v0.1.0/mod.ts"`, and redirects
+// floating imports such as `eggs/mod.ts` or `eggs@^0.1/mod.ts` to them
+use actix_web::error::ErrorBadGateway;
+use actix_web::http::header;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use futures::TryStreamExt;
+
+use crate::db;
+use crate::error::ApiError;
+use crate::storage;
+use crate::utils::{content_type, parse_version_req, sanitize_path};
+use crate::AppState;
+
+// versions never change once published, so their files can be cached forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+// floating imports move on with every publish, so they are only cached briefly
+const FLOATING: &str = "public, max-age=300";
+
+// Serve `/<name>@<version>/<path>` from the version's file manifest. Any other
+// version spec, or none at all, is redirected to the version it resolves to.
+pub async fn module_file(
+    st: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (module, file) = path.into_inner();
+    let (name, spec) = match module.split_once('@') {
+        Some((name, spec)) => (name, Some(spec)),
+        None => (module.as_str(), None),
+    };
+    let version = match spec {
+        Some(spec) => db::get_package_version(&st.pool, name.to_string(), spec.to_string()).await,
+        None => Err(ApiError::NotFound),
+    };
+    let version = match version {
+        Ok(version) => version,
+        Err(ApiError::NotFound) => return floating_redirect(&st, name, spec, &file).await,
+        Err(e) => return Err(e.into()),
+    };
+    if version.malicious {
+        let e = ApiError::Unauthorized("This version was flagged as malicious".to_string());
+        return Err(e.into());
+    }
+    let path = manifest_path(&version.prefix, &file);
+    let tx_id = match version.files.iter().find(|f| f.path == path) {
+        Some(entry) => &entry.tx_id,
+        None => return Err(ApiError::NotFound.into()),
+    };
+
+    // the storage transaction of a file is as immutable as its contents
+    let etag = format!("\"{}\"", tx_id);
+    if if_none_match(&req, &etag) {
+        return Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::CACHE_CONTROL, IMMUTABLE)
+            .finish());
+    }
+    let res = storage::fetch(tx_id).await.map_err(ErrorBadGateway)?;
+    // every import of a version goes through its entry module, count those
+    if sanitize_path(&file) == sanitize_path(&version.entry) {
+        let pool = st.pool.clone();
+        let package = version.package.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = db::count_download(&pool, &package).await {
+                eprintln!("failed to count download of {}: {}", package, e);
+            }
+        });
+    }
+    Ok(HttpResponse::Ok()
+        .content_type(content_type(&path))
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, IMMUTABLE)
+        .streaming(res.bytes_stream().map_err(ErrorBadGateway)))
+}
+
+// Redirect a floating import to the exact version it currently resolves to:
+// none or `latest` to the latest stable version (or the latest one if nothing
+// is stable yet), a full version to the published one equal to it, and
+// anything else through semver range resolution.
+async fn floating_redirect(
+    st: &AppState,
+    name: &str,
+    spec: Option<&str>,
+    file: &str,
+) -> Result<HttpResponse, Error> {
+    let version = match spec {
+        None | Some("latest") => db::get_package(&st.pool, name.to_string())
+            .await
+            .map(|package| package.latest_stable_version.or(package.latest_version)),
+        Some(spec) => {
+            let range = parse_version_req(spec).ok_or_else(|| {
+                ApiError::Validation(format!("{} is not a valid version range", spec))
+            })?;
+            db::resolve_version(&st.pool, name.to_string(), &range)
+                .await
+                .map(|version| Some(version.version))
+        }
+    };
+    let version = version?.ok_or(ApiError::NotFound)?;
+    Ok(HttpResponse::Found()
+        .header(header::LOCATION, format!("/{}@{}/{}", name, version, file))
+        .header(header::CACHE_CONTROL, FLOATING)
+        .finish())
+}
+
+// The manifest key of a requested file: its path below the version's prefix
+pub fn manifest_path(prefix: &str, file: &str) -> String {
+    let prefix = sanitize_path(prefix);
+    let file = sanitize_path(file);
+    if prefix.is_empty() {
+        format!("/{}", file)
+    } else {
+        format!("/{}/{}", prefix, file)
+    }
+}
+
+// The inverse of `manifest_path`: the path a manifest entry is imported by, if
+// it lies below the version's prefix
+pub fn import_path(prefix: &str, manifest_path: &str) -> Option<String> {
+    let prefix = sanitize_path(prefix);
+    let path = manifest_path.trim_start_matches('/');
+    if prefix.is_empty() {
+        return Some(path.to_string());
+    }
+    path.strip_prefix(&prefix)
+        .and_then(|x| x.strip_prefix('/'))
+        .map(|x| x.to_string())
+}
+
+// whether the client's cached copy, named by If-None-Match, is still current
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers()
+        .get_all(header::IF_NONE_MATCH)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn manifest_path_below_prefix() {
+        assert_eq!(manifest_path("", "mod.ts"), "/mod.ts");
+        assert_eq!(manifest_path("/", "src/mod.ts"), "/src/mod.ts");
+        assert_eq!(manifest_path("dist/", "mod.ts"), "/dist/mod.ts");
+        assert_eq!(manifest_path("dist", "../../mod.ts"), "/dist/mod.ts");
+    }
+
+    #[test]
+    fn import_path_inverts_manifest_path() {
+        assert_eq!(import_path("", "/src/mod.ts"), Some("src/mod.ts".to_string()));
+        assert_eq!(import_path("dist/", "/dist/mod.ts"), Some("mod.ts".to_string()));
+        let path = manifest_path("dist", "lib/mod.ts");
+        assert_eq!(import_path("dist", &path), Some("lib/mod.ts".to_string()));
+    }
+
+    #[test]
+    fn import_path_outside_prefix() {
+        assert_eq!(import_path("dist", "/README.md"), None);
+        assert_eq!(import_path("dist", "/distribution/mod.ts"), None);
+    }
+
+    #[test]
+    fn if_none_match_current_tags() {
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "\"a\", W/\"b\"")
+            .to_http_request();
+        assert!(if_none_match(&req, "\"a\""));
+        assert!(if_none_match(&req, "\"b\""));
+        assert!(!if_none_match(&req, "\"c\""));
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, "*")
+            .to_http_request();
+        assert!(if_none_match(&req, "\"c\""));
+    }
+
+    #[test]
+    fn if_none_match_without_header() {
+        let req = TestRequest::default().to_http_request();
+        assert!(!if_none_match(&req, "\"a\""));
+    }
+}
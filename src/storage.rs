@@ -0,0 +1,12 @@
+// Reads published files back from storage through an Arweave gateway
+
+// fetch the contents of the storage transaction `tx_id`
+pub async fn fetch(tx_id: &str) -> Result<reqwest::Response, reqwest::Error> {
+    let gateway =
+        dotenv::var("ARWEAVE_GATEWAY").unwrap_or_else(|_| "https://arweave.net".to_string());
+    reqwest::Client::new()
+        .get(&format!("{}/{}", gateway.trim_end_matches('/'), tx_id))
+        .send()
+        .await?
+        .error_for_status()
+}
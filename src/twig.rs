@@ -7,19 +7,22 @@ struct NewTx {
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct PostTx {
-    tx_id: String,
-    name: String,
-    relative_path: String,
+pub struct PostTx {
+    pub tx_id: String,
+    pub name: String,
+    pub relative_path: String,
 }
 
-pub async fn tx(tmp_id: String) -> Result<PostTx, reqwest::Error> {
-    let new_tx = NewTx { tmp_id: tmp_id };
-    let tx_done: PostTx = reqwest::Client::new()
-        .post("http://localhost:3000/tx/new")
+// push the files staged in `tmp/<tmp_id>` to storage, one transaction per file
+pub async fn tx(tmp_id: String) -> Result<Vec<PostTx>, reqwest::Error> {
+    let host = dotenv::var("TWIG_HOST").unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let new_tx = NewTx { tmp_id };
+    let tx_done: Vec<PostTx> = reqwest::Client::new()
+        .post(&format!("{}/tx/new", host))
         .json(&new_tx)
         .send()
         .await?
+        .error_for_status()?
         .json()
         .await?;
 
@@ -1,8 +1,13 @@
 // utils used by the nestapi.
+use hmac::{Hmac, Mac, NewMac};
+use semver::{Version, VersionReq};
+use sha2::Sha256;
+use std::path::Path;
+use std::str::FromStr;
 use uuid::Uuid;
 
 // get the first element of a vector
-pub fn first<T>(v: &Vec<T>) -> Option<&T> {
+pub fn first<T>(v: &[T]) -> Option<&T> {
     v.first()
 }
 
@@ -11,6 +16,118 @@ pub fn create_api_key() -> String {
     Uuid::new_v4().to_simple().to_string()
 }
 
+// keyed hash under which API keys and tokens are stored, using API_KEY_SECRET
+pub fn hash_api_key(key: &str) -> String {
+    let secret = dotenv::var("API_KEY_SECRET").unwrap_or_default();
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(key.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// the visible part of an API key, used to tell keys apart
+pub fn api_key_prefix(key: &str) -> String {
+    key.chars().take(8).collect()
+}
+
+// read a setting from the environment, falling back to `default`
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    dotenv::var(key)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(default)
+}
+
+// parse a package version as semver, allowing the conventional `v` prefix
+pub fn parse_version(input: &str) -> Option<Version> {
+    Version::parse(input.strip_prefix('v').unwrap_or(input)).ok()
+}
+
+// parse a semver range such as `^1.2`, `~v1.2.3`, `1.x` or `>=1.2 <2`. Versions
+// may carry the `v` prefix and comparators may be separated by spaces. A bare
+// full version such as `1.2.3` pins that exact version, as in Deno and npm,
+// rather than the caret range Cargo reads it as.
+pub fn parse_version_req(input: &str) -> Option<VersionReq> {
+    let mut comparators: Vec<String> = vec![];
+    let mut op = String::new();
+    for token in input.split(|c: char| c == ',' || c.is_whitespace()) {
+        if token.is_empty() {
+            continue;
+        }
+        // an operator written apart from its version, as in `>= 1.2`
+        if token.chars().all(|c| "<>=~^".contains(c)) {
+            op.push_str(token);
+            continue;
+        }
+        let version_at = token.find(|c: char| !"<>=~^".contains(c)).unwrap_or(0);
+        let (token_op, version) = token.split_at(version_at);
+        let version = version.strip_prefix('v').unwrap_or(version);
+        if op.is_empty() && token_op.is_empty() && Version::parse(version).is_ok() {
+            op.push('=');
+        }
+        comparators.push(format!("{}{}{}", op, token_op, version));
+        op.clear();
+    }
+    if !op.is_empty() {
+        return None;
+    }
+    VersionReq::parse(&comparators.join(", ")).ok()
+}
+
+// the Content-Type Deno expects for a module file, judged by its extension
+pub fn content_type(path: &str) -> &'static str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_lowercase());
+    match extension.as_deref() {
+        Some("ts") | Some("mts") | Some("cts") => "application/typescript; charset=utf-8",
+        Some("tsx") => "text/tsx; charset=utf-8",
+        Some("js") | Some("mjs") | Some("cjs") => "application/javascript; charset=utf-8",
+        Some("jsx") => "text/jsx; charset=utf-8",
+        Some("json") | Some("map") => "application/json; charset=utf-8",
+        Some("wasm") => "application/wasm",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
+
+// wrap a position in a list into an opaque pagination cursor
+pub fn encode_cursor(position: &str) -> String {
+    base64::encode_config(position, base64::URL_SAFE_NO_PAD)
+}
+
+// the position inside a pagination cursor, if it is one
+pub fn decode_cursor(cursor: &str) -> Option<String> {
+    base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|x| String::from_utf8(x).ok())
+}
+
+// the position of a row in a list ordered by `value` and then by `name`. Both
+// may contain any character, so they are kept apart as a JSON pair.
+pub fn encode_position(value: &str, name: &str) -> String {
+    serde_json::json!([value, name]).to_string()
+}
+
+// the sort value and name a position was made of
+pub fn decode_position(position: &str) -> Option<(String, String)> {
+    serde_json::from_str(position).ok()
+}
+
+// turn user input into a Postgres tsquery matching every word as a prefix, so
+// partially typed words match too
+pub fn prefix_tsquery(input: &str) -> String {
+    input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("{}:*", x))
+        .collect::<Vec<String>>()
+        .join(" & ")
+}
+
 // normalize string
 pub fn normalize(input: &str) -> String {
     input
@@ -26,3 +143,218 @@ pub fn normalize(input: &str) -> String {
         })
         .collect()
 }
+
+// the first path segments of the API's own routes. Module files are served
+// below `/<package>`, so packages can't take these names.
+const RESERVED_NAMES: &[&str] = &[
+    "graphql",
+    "graphiql",
+    "package",
+    "resolve",
+    "intellisense",
+    ".well-known",
+];
+
+// whether `name` is taken by one of the API's own routes
+pub fn is_reserved_name(name: &str) -> bool {
+    let name = normalize(name);
+    RESERVED_NAMES.iter().any(|x| normalize(x) == name)
+}
+
+// the longest package name "packages" stores
+const MAX_PACKAGE_NAME_LEN: usize = 40;
+
+// whether `name` can be a package name: lowercase letters, digits, `_` and
+// `-`, which import URLs and intellisense completions take as they are
+pub fn is_valid_package_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_PACKAGE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+}
+
+// sanitize every segment of an uploaded relative path, dropping `.` and `..`
+pub fn sanitize_path(input: &str) -> String {
+    input
+        .split(['/', '\\'])
+        .filter(|x| !x.is_empty() && *x != "." && *x != "..")
+        .map(sanitize_filename::sanitize)
+        .filter(|x| !x.is_empty())
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(range: &str, version: &str) -> bool {
+        let range = parse_version_req(range).expect("valid range");
+        range.matches(&parse_version(version).expect("valid version"))
+    }
+
+    #[test]
+    fn parse_version_strips_v_prefix() {
+        assert_eq!(parse_version("v1.2.3"), Version::parse("1.2.3").ok());
+        assert_eq!(parse_version("1.2.3-beta.1"), Version::parse("1.2.3-beta.1").ok());
+        assert_eq!(parse_version("1.2"), None);
+        assert_eq!(parse_version("vv1.2.3"), None);
+    }
+
+    #[test]
+    fn parse_version_req_caret_and_tilde() {
+        assert!(matches("^1.2", "1.9.0"));
+        assert!(!matches("^1.2", "2.0.0"));
+        assert!(matches("~v1.2.3", "1.2.9"));
+        assert!(!matches("~v1.2.3", "1.3.0"));
+    }
+
+    #[test]
+    fn parse_version_req_v_prefix() {
+        assert!(matches("v1.2.3", "1.2.3"));
+        assert!(matches("=v1.2.3", "v1.2.3"));
+        assert!(!matches("=v1.2.3", "1.2.4"));
+    }
+
+    #[test]
+    fn parse_version_req_pins_full_versions() {
+        assert!(matches("1.2.3", "1.2.3"));
+        assert!(!matches("1.2.3", "1.2.5"));
+        assert!(matches("1.2.3-beta.1", "1.2.3-beta.1"));
+        assert!(!matches("1.2.3-beta.1", "1.2.3"));
+        assert!(matches("1.2", "1.9.0"));
+        assert!(matches("^1.2.3", "1.2.5"));
+    }
+
+    #[test]
+    fn parse_version_req_split_operators() {
+        assert!(matches(">= 1.2", "1.2.0"));
+        assert!(!matches(">= 1.2", "1.1.9"));
+        assert!(matches(">=1.2 <2", "1.9.9"));
+        assert!(!matches(">=1.2 <2", "2.0.0"));
+        assert!(matches(">= v1.2, < v2", "1.5.0"));
+    }
+
+    #[test]
+    fn parse_version_req_wildcards() {
+        assert!(matches("1.x", "1.4.0"));
+        assert!(!matches("1.x", "2.0.0"));
+        assert!(matches("*", "3.1.4"));
+    }
+
+    #[test]
+    fn parse_version_req_prereleases() {
+        assert!(!matches("^1.2", "1.3.0-beta.1"));
+        assert!(matches(">=1.3.0-beta", "1.3.0-beta.2"));
+        assert!(matches(">=1.3.0-beta", "1.3.0"));
+    }
+
+    #[test]
+    fn parse_version_req_rejects_malformed() {
+        assert!(parse_version_req(">=1.2 <").is_none());
+        assert!(parse_version_req(">=").is_none());
+        assert!(parse_version_req("abc").is_none());
+        assert!(parse_version_req("1.2.3.4").is_none());
+    }
+
+    #[test]
+    fn sanitize_path_drops_traversal() {
+        assert_eq!(sanitize_path("../../etc/passwd"), "etc/passwd");
+        assert_eq!(sanitize_path("/src/./mod.ts"), "src/mod.ts");
+        assert_eq!(sanitize_path("src\\..\\mod.ts"), "src/mod.ts");
+        assert_eq!(sanitize_path("a//b/"), "a/b");
+        assert_eq!(sanitize_path("/"), "");
+    }
+
+    #[test]
+    fn sanitize_path_sanitizes_segments() {
+        assert_eq!(sanitize_path("src/mod?.ts"), "src/mod.ts");
+        assert_eq!(sanitize_path("src/<>/mod.ts"), "src/mod.ts");
+    }
+
+    #[test]
+    fn content_type_by_extension() {
+        assert_eq!(content_type("/mod.ts"), "application/typescript; charset=utf-8");
+        assert_eq!(content_type("/MOD.TS"), "application/typescript; charset=utf-8");
+        assert_eq!(content_type("/app.tsx"), "text/tsx; charset=utf-8");
+        assert_eq!(content_type("/lib/index.mjs"), "application/javascript; charset=utf-8");
+        assert_eq!(content_type("/deps.json"), "application/json; charset=utf-8");
+        assert_eq!(content_type("/lib.wasm"), "application/wasm");
+    }
+
+    #[test]
+    fn content_type_falls_back_to_octet_stream() {
+        assert_eq!(content_type("/LICENSE"), "application/octet-stream");
+        assert_eq!(content_type("/archive.tar.gz"), "application/octet-stream");
+        assert_eq!(content_type("/.ts"), "application/octet-stream");
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        for position in &["", "eggs", "31|eggs", "2020-06-27T10:15:55+00:00|a b|c", "ünïcode"] {
+            let cursor = encode_cursor(position);
+            assert!(cursor.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+            assert_eq!(decode_cursor(&cursor).as_deref(), Some(*position));
+        }
+    }
+
+    #[test]
+    fn decode_cursor_rejects_garbage() {
+        assert_eq!(decode_cursor("not a cursor!"), None);
+        // valid base64, but not UTF-8
+        assert_eq!(decode_cursor("_w"), None);
+    }
+
+    #[test]
+    fn position_round_trip() {
+        for (value, name) in &[("eggs", "eggs"), ("a|b", "c|d"), ("31", "\"[]\\"), ("", "")] {
+            let position = encode_position(value, name);
+            assert_eq!(
+                decode_position(&position),
+                Some((value.to_string(), name.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn decode_position_rejects_garbage() {
+        assert_eq!(decode_position("31|eggs"), None);
+        assert_eq!(decode_position("[\"31\"]"), None);
+        assert_eq!(decode_position("[31, \"eggs\"]"), None);
+    }
+
+    #[test]
+    fn prefix_tsquery_matches_word_prefixes() {
+        assert_eq!(prefix_tsquery("eggs"), "eggs:*");
+        assert_eq!(prefix_tsquery("  desktop   autom "), "desktop:* & autom:*");
+        assert_eq!(prefix_tsquery("deno_std"), "deno:* & std:*");
+    }
+
+    #[test]
+    fn prefix_tsquery_drops_operators() {
+        assert_eq!(prefix_tsquery("a & !b | (c:*)"), "a:* & b:* & c:*");
+        assert_eq!(prefix_tsquery("&|!"), "");
+        assert_eq!(prefix_tsquery(""), "");
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert!(is_reserved_name("resolve"));
+        assert!(is_reserved_name("GraphQL"));
+        assert!(is_reserved_name(".well-known"));
+        assert!(!is_reserved_name("eggs"));
+        assert!(!is_reserved_name("resolver"));
+    }
+
+    #[test]
+    fn package_name_charset() {
+        assert!(is_valid_package_name("deno-sass_2"));
+        assert!(is_valid_package_name(&"a".repeat(40)));
+        assert!(!is_valid_package_name(""));
+        assert!(!is_valid_package_name(&"a".repeat(41)));
+        for name in &["Sass", "sass@1", "a/b", "a%20b", "a b", "a.b", "ünï"] {
+            assert!(!is_valid_package_name(name), "{}", name);
+        }
+    }
+}